@@ -0,0 +1,45 @@
+use std::collections::HashMap;
+extern crate burst;
+
+use burst::{MachineSetup, BurstBuilder, Machine, LocalBackend};
+
+/*
+ * Runs the same kind of experiment as test1, but on sshd processes on localhost instead of EC2.
+ * Needs sshd installed at /usr/sbin/sshd, no AWS credentials are used.
+ */
+fn main() {
+    let mut b = BurstBuilder::default();
+    b.use_term_logger();
+    b.set_backend(LocalBackend::new().expect("failed to prepare local machines"));
+    b.add_set(
+        "server",
+        1,
+        MachineSetup::new("local", "none", |sess| {
+            sess.cmd("pwd").map(|out| {
+                println!("{}", out);
+            })
+        })
+    );
+    b.add_set(
+        "client",
+        2,
+        MachineSetup::new("local", "none", |sess| {
+            sess.cmd("date").map(|out| {
+                println!("{}", out);
+            })
+        })
+    );
+
+    let res = b.run(|vms: HashMap<String, Vec<Machine>>| {
+        println!("==> {}:{}", vms["server"][0].public_ip, vms["server"][0].ssh_port);
+        for c in &vms["client"] {
+            println!(" -> {}:{}", c.public_ip, c.ssh_port);
+        }
+        Ok(())
+    });
+
+    match res {
+        Ok(()) => println!("local run succeeded"),
+        Err(e) => println!("local run failed: {}", e),
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use async_trait::async_trait;
+use failure::{Error, Fail, ResultExt};
+
+use crate::ledger::Ledger;
+use crate::{Machine, MachineSetup};
+
+/*
+ * Backend is the provider that machines of a burst run are provisioned from.
+ * BurstBuilder::run only talks to a backend through this trait, so the EC2 spot flow is just one
+ * implementation and other providers (or fakes for testing experiment harnesses) can be plugged in
+ * with BurstBuilder::set_backend.
+ *
+ * provision: brings up `number` machines for every named machine set in `sets` and reports their addresses.
+ *            The returned map is keyed by machine set name, the ssh field of every Machine is left as None.
+ *            Sets with a Fulfillment may come back with fewer machines, but never fewer than its min;
+ *            provision fails with InsufficientMachines instead.
+ * private_key: path of the private key that authenticates ssh sessions to the provisioned machines,
+ *              or None if the key is only held by the ssh-agent.
+ * ssh_user: user that ssh sessions to the provisioned machines log in as.
+ * teardown: releases everything the backend created and returns whatever it could not release.
+ *           It is called once after every run, including runs where provision failed half way,
+ *           so it must only clean up what was actually created. Failures are logged, not returned.
+ * set_ledger: hands the backend a ledger to record every resource in as soon as it is created or released.
+ *             Backends whose resources cannot outlive the process may ignore it.
+ * set_tags: hands the backend key/value tags (run id, owner, ...) to attach to every resource it creates,
+ *           so that they can be attributed and found again. Backends without tagging may ignore them.
+ * set_max_duration: hands the backend the longest a run may take. Backends whose machines can bill on
+ *                   after the process is gone should make them shut themselves down after it.
+ * set_key_pair: tells the backend which key pair to let ssh sessions in with, see KeyPair.
+ *               Backends that bring their own keys may ignore it.
+ */
+#[async_trait]
+pub trait Backend: Send {
+    async fn provision(
+        &mut self,
+        log: &slog::Logger,
+        sets: &HashMap<String, (MachineSetup, u32)>,
+    ) -> Result<HashMap<String, Vec<Machine>>, Error>;
+
+    fn private_key(&self) -> Option<&Path>;
+
+    fn ssh_user(&self) -> &str;
+
+    async fn teardown(&mut self, log: &slog::Logger) -> Vec<Resource>;
+
+    fn set_ledger(&mut self, _ledger: Ledger) {}
+
+    fn set_tags(&mut self, _tags: &[(String, String)]) {}
+
+    fn set_max_duration(&mut self, _max_duration: Duration) {}
+
+    fn set_key_pair(&mut self, _key_pair: KeyPair) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/*
+ * KeyPair is the key that ssh sessions to the machines of a run authenticate with.
+ * Generated: a key pair created for the run, whose private key only lives in a temporary file.
+ * Local: the ed25519 key at the given path, generated there if missing, whose public half (the path
+ *        with .pub appended) is imported for the run, so that it can also be used to ssh in manually.
+ * Existing: a key pair that already exists under `name` and is neither created nor deleted by the run.
+ *           Sessions authenticate with `private_key`, or through the ssh-agent if it is None.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyPair {
+    Generated,
+    Local(PathBuf),
+    Existing { name: String, private_key: Option<PathBuf> },
+}
+
+/*
+ * Resource is something a backend created on behalf of a run, and that costs money or clutters
+ * the account for as long as it exists.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Resource {
+    Instance(String),
+    SpotRequest(String),
+    SecurityGroup(String),
+    KeyPair(String),
+    Process(u32),
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Resource::Instance(ref id) => write!(f, "instance {}", id),
+            Resource::SpotRequest(ref id) => write!(f, "spot request {}", id),
+            Resource::SecurityGroup(ref id) => write!(f, "security group {}", id),
+            Resource::KeyPair(ref name) => write!(f, "key pair {}", name),
+            Resource::Process(pid) => write!(f, "process {}", pid),
+        }
+    }
+}
+
+impl FromStr for Resource {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let (kind, id) = s.rsplit_once(' ').ok_or_else(|| failure::format_err!("not a resource: {}", s))?;
+        let id = id.to_string();
+        Ok(match kind {
+            "instance" => Resource::Instance(id),
+            "spot request" => Resource::SpotRequest(id),
+            "security group" => Resource::SecurityGroup(id),
+            "key pair" => Resource::KeyPair(id),
+            "process" => Resource::Process(id.parse::<u32>().context("process id is not a number")?),
+            _ => return Err(failure::format_err!("unknown resource kind: {}", kind)),
+        })
+    }
+}
+
+/*
+ * TeardownIncomplete is the error BurstBuilder::run returns when teardown left resources behind,
+ * which then have to be cleaned up by hand.
+ * If the run itself failed as well, its error is kept as the cause.
+ */
+#[derive(Debug)]
+pub struct TeardownIncomplete {
+    pub leftovers: Vec<Resource>,
+    pub run_error: Option<Error>,
+}
+
+impl fmt::Display for TeardownIncomplete {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "teardown left {} resources behind:", self.leftovers.len())?;
+        for resource in &self.leftovers {
+            write!(f, " {};", resource)?;
+        }
+        if let Some(ref e) = self.run_error {
+            write!(f, " the run failed too: {}", e)?;
+        }
+        Ok(())
+    }
+}
+
+impl Fail for TeardownIncomplete {
+    fn cause(&self) -> Option<&dyn Fail> {
+        self.run_error.as_ref().map(Error::as_fail)
+    }
+}
+
+/*
+ * InsufficientMachines is the error provisioning fails with when fewer machines of a set could be obtained
+ * than its Fulfillment (or, without one, add_set) asks for at least.
+ */
+#[derive(Debug)]
+pub struct InsufficientMachines {
+    pub set: String,
+    pub wanted: u32,
+    pub min: u32,
+    pub obtained: u32,
+}
+
+impl fmt::Display for InsufficientMachines {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "only {} of {} {} machines could be obtained, at least {} were needed",
+            self.obtained, self.wanted, self.set, self.min
+        )
+    }
+}
+
+impl Fail for InsufficientMachines {}
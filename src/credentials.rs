@@ -0,0 +1,84 @@
+use std::sync::Arc;
+use async_trait::async_trait;
+use failure::{Error, ResultExt};
+use rusoto_core::Region;
+use rusoto_credential::{
+    AutoRefreshingProvider, AwsCredentials, CredentialsError, ProfileProvider, ProvideAwsCredentials,
+};
+use rusoto_sts::{StsAssumeRoleSessionCredentialsProvider, StsClient};
+
+/*
+ * Credentials are where the AWS credentials of a run come from, shared by the EC2 clients of every region.
+ * Any ProvideAwsCredentials will do; BurstBuilder::set_credentials wraps it in one of these.
+ */
+#[derive(Clone)]
+pub struct Credentials(Arc<dyn ProvideAwsCredentials + Send + Sync>);
+
+impl Credentials {
+    pub fn new<P: ProvideAwsCredentials + Send + Sync + 'static>(provider: P) -> Self {
+        Credentials(Arc::new(provider))
+    }
+
+    /*
+     * The standard chain: the AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY environment variables,
+     * then the profile named by AWS_PROFILE (or the default one) in ~/.aws/credentials,
+     * then the container and instance metadata endpoints.
+     */
+    pub fn default_chain() -> Result<Self, Error> {
+        let provider = rusoto_credential::DefaultCredentialsProvider::new()
+            .context("failed to set up the default aws credentials chain")?;
+        Ok(Self::new(provider))
+    }
+
+    /*
+     * The named profile of ~/.aws/credentials (or wherever AWS_SHARED_CREDENTIALS_FILE points).
+     */
+    pub fn profile(name: &str) -> Result<Self, Error> {
+        let mut provider = ProfileProvider::new().context("failed to locate the aws credentials file")?;
+        provider.set_profile(name);
+        Ok(Self::new(provider))
+    }
+}
+
+#[async_trait]
+impl ProvideAwsCredentials for Credentials {
+    async fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        self.0.credentials().await
+    }
+}
+
+/*
+ * AssumeRole provides the temporary credentials of an IAM role, e.g. one in a separate experiment account,
+ * obtained from STS with the credentials of `source`. They are renewed shortly before they expire.
+ * external_id is passed along for roles whose trust policy requires one.
+ */
+pub struct AssumeRole(AutoRefreshingProvider<StsAssumeRoleSessionCredentialsProvider>);
+
+impl AssumeRole {
+    pub fn new<P>(source: P, role_arn: &str, session_name: &str, external_id: Option<&str>) -> Result<Self, Error>
+    where P: ProvideAwsCredentials + Send + Sync + 'static
+    {
+        let sts = StsClient::new_with(
+            rusoto_core::HttpClient::new().context("failed to create tls session for the sts client")?,
+            source,
+            Region::UsEast1,
+        );
+        let provider = AutoRefreshingProvider::new(StsAssumeRoleSessionCredentialsProvider::new(
+            sts,
+            role_arn.to_string(),
+            session_name.to_string(),
+            external_id.map(str::to_string),
+            None,
+            None,
+            None,
+        )).context("failed to set up assume-role credentials")?;
+        Ok(AssumeRole(provider))
+    }
+}
+
+#[async_trait]
+impl ProvideAwsCredentials for AssumeRole {
+    async fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        self.0.credentials().await
+    }
+}
@@ -0,0 +1,226 @@
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use failure::{Error, ResultExt};
+use rand::Rng;
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+
+use crate::ssh::{quote, CommandFailed, Session};
+use crate::Machine;
+
+// how many random ports serve tries before giving up, in case the ones it picks are taken
+const SERVE_ATTEMPTS: usize = 5;
+// the exit status of the serving command when the server did not come up, e.g. because its port was taken
+const NOT_LISTENING: i32 = 3;
+
+/*
+ * Artifact is a local file, typically a binary built for the machines, that is copied to `remote`
+ * on every machine of a set before its setup routine runs, see MachineSetup::add_artifact.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Artifact {
+    pub local: PathBuf,
+    pub remote: String,
+}
+
+/*
+ * Copies every artifact to every machine of the set `name`, and checks that each copy is intact and executable.
+ * With fan_out, an artifact is uploaded to the first machine only, and the others fetch it from there
+ * over the private network (with curl or wget, from a python3 http.server), which spares the local uplink
+ * for large sets. Otherwise it is uploaded to all machines in parallel.
+ */
+pub(crate) fn deploy(
+    log: &slog::Logger,
+    name: &str,
+    machines: &mut [Machine],
+    artifacts: &[Artifact],
+    fan_out: bool,
+) -> Result<(), Error> {
+    for artifact in artifacts {
+        let checksum = sha256(&artifact.local)?;
+        info!(log, "deploying {}", artifact.local.display(); "set" => name, "to" => &artifact.remote, "sha256" => &checksum);
+        if fan_out && machines.len() > 1 {
+            let (seed, rest) = machines.split_first_mut().expect("machines to deploy to");
+            upload(seed, artifact, &checksum)?;
+            let server = serve(seed, artifact)?;
+            let fetched: Result<Vec<()>, Error> = rest
+                .par_iter_mut()
+                .map(|machine| fetch(machine, &server.url, artifact, &checksum))
+                .collect();
+            stop_serving(seed, &server).unwrap_or_else(|e| warn!(log, "{}", e));
+            fetched?;
+        } else {
+            machines
+                .par_iter_mut()
+                .map(|machine| upload(machine, artifact, &checksum))
+                .collect::<Result<Vec<()>, Error>>()?;
+        }
+    }
+    Ok(())
+}
+
+/*
+ * The hex encoded SHA-256 of the local file at `path`.
+ */
+fn sha256(path: &Path) -> Result<String, Error> {
+    let mut file = fs::File::open(path).context(format!("failed to open artifact {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).context(format!("failed to read artifact {}", path.display()))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn session(machine: &mut Machine) -> Result<&mut Session, Error> {
+    let ip = &machine.public_ip;
+    machine.ssh.as_mut().ok_or_else(|| failure::format_err!("no ssh session to machine {}", ip))
+}
+
+/*
+ * Creates the directory `remote` goes in, if it has one.
+ */
+fn mkdir_for(sess: &mut Session, remote: &str) -> Result<(), Error> {
+    match Path::new(remote).parent().and_then(Path::to_str) {
+        Some(dir) if !dir.is_empty() => {
+            sess.exec_checked(&format!("mkdir -p {}", quote(dir)))?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn upload(machine: &mut Machine, artifact: &Artifact, checksum: &str) -> Result<(), Error> {
+    let ip = machine.public_ip.clone();
+    let sess = session(machine)?;
+    mkdir_for(sess, &artifact.remote)?;
+    sess.upload(&artifact.local, Path::new(&artifact.remote))
+        .context(format!("failed to upload {} to {}", artifact.local.display(), ip))?;
+    verify(sess, &ip, artifact, checksum)
+}
+
+/*
+ * Makes the deployed artifact executable, and fails if its checksum is not the local one.
+ */
+fn verify(sess: &mut Session, ip: &str, artifact: &Artifact, checksum: &str) -> Result<(), Error> {
+    let remote = quote(&artifact.remote);
+    let out = sess.exec_checked(&format!("chmod +x {} && sha256sum {}", remote, remote))?;
+    let found = out.stdout.split_whitespace().next().unwrap_or_default();
+    if found != checksum {
+        return Err(failure::format_err!(
+            "checksum mismatch for {} on {}: expected {}, found {}", artifact.remote, ip, checksum, found
+        ));
+    }
+    Ok(())
+}
+
+/*
+ * Server is an http server on the first machine of a set that serves a copy of an artifact from `dir`.
+ */
+struct Server {
+    url: String,
+    pid: String,
+    dir: String,
+}
+
+/*
+ * Serves a copy of the uploaded artifact over http from `seed`, on a random port. Before this returns the
+ * server is seen serving a marker file only its directory has, so that a port some other server already
+ * listens on does not pass for it; if it does not come up, another port is tried.
+ */
+fn serve(seed: &mut Machine, artifact: &Artifact) -> Result<Server, Error> {
+    let ip = seed.private_ip.clone();
+    let sess = session(seed)?;
+    for _ in 0..SERVE_ATTEMPTS {
+        let port: u16 = rand::thread_rng().gen_range(20000..30000);
+        let cmd = format!(
+            "dir=$(mktemp -d /tmp/burst-deploy-XXXXXX) && cp {remote} \"$dir/artifact\" && cd \"$dir\" && \
+             marker=$(basename \"$dir\") && touch \"$marker\" || exit 1
+             nohup python3 -m http.server {port} --bind {ip} </dev/null >/dev/null 2>&1 &
+             pid=$!
+             for i in $(seq 50); do
+                 if python3 -c 'import sys, urllib.request; urllib.request.urlopen(sys.argv[1], timeout=1)' \
+                     \"http://{ip}:{port}/$marker\" 2>/dev/null; then
+                     echo $pid \"$dir\"; exit 0
+                 fi
+                 kill -0 $pid 2>/dev/null || break
+                 sleep 0.1
+             done
+             kill $pid 2>/dev/null; rm -rf \"$dir\"; exit {not_listening}",
+            remote = quote(&artifact.remote),
+            port = port,
+            ip = ip,
+            not_listening = NOT_LISTENING,
+        );
+        let out = sess.exec(&cmd).context(format!("failed to serve {} from {}", artifact.remote, ip))?;
+        if out.status == NOT_LISTENING {
+            continue;
+        }
+        if !out.success() {
+            return Err(Error::from(CommandFailed { cmd, output: out })
+                .context(format!("failed to serve {} from {}", artifact.remote, ip))
+                .into());
+        }
+        let mut out = out.stdout.split_whitespace();
+        return match (out.next(), out.next()) {
+            (Some(pid), Some(dir)) => Ok(Server {
+                url: format!("http://{}:{}/artifact", ip, port),
+                pid: pid.to_string(),
+                dir: dir.to_string(),
+            }),
+            _ => Err(failure::format_err!("failed to serve {} from {}", artifact.remote, ip)),
+        };
+    }
+    Err(failure::format_err!(
+        "failed to serve {} from {}: the server did not come up on any of {} ports tried",
+        artifact.remote, ip, SERVE_ATTEMPTS
+    ))
+}
+
+/*
+ * Stops the http server, and deletes the copy it served.
+ */
+fn stop_serving(seed: &mut Machine, server: &Server) -> Result<(), Error> {
+    session(seed)?
+        .exec_checked(&format!("kill {}; rm -rf {}", server.pid, quote(&server.dir)))
+        .context("failed to stop serving the artifact")?;
+    Ok(())
+}
+
+/*
+ * Makes `machine` fetch the artifact from `url`, retrying a few times, and fails if no attempt succeeded.
+ */
+fn fetch(machine: &mut Machine, url: &str, artifact: &Artifact, checksum: &str) -> Result<(), Error> {
+    let ip = machine.public_ip.clone();
+    let url = quote(url);
+    let part = quote(&format!("{}.part", artifact.remote));
+    let sess = session(machine)?;
+    mkdir_for(sess, &artifact.remote)?;
+    sess.exec_checked(&format!(
+        "ok=; for i in $(seq 20); do (curl -sSf -o {part} {url} || wget -q -O {part} {url}) && ok=1 && break; sleep 0.5; done; \
+         [ -n \"$ok\" ] && mv {part} {remote}",
+        part = part,
+        url = url,
+        remote = quote(&artifact.remote)
+    )).context(format!("failed to fetch {} on {}", artifact.remote, ip))?;
+    verify(sess, &ip, artifact, checksum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums_local_artifacts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server");
+        fs::write(&path, b"abc").unwrap();
+        assert_eq!(sha256(&path).unwrap(), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert!(sha256(&dir.path().join("missing")).is_err());
+    }
+}
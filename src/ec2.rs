@@ -0,0 +1,2063 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use failure::{Error, ResultExt};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use rusoto_ec2::Ec2;
+
+use crate::backend::{Backend, InsufficientMachines, KeyPair, Resource};
+use crate::credentials::Credentials;
+use crate::ledger::Ledger;
+use crate::local;
+use crate::poll::{Backoff, Poll};
+use crate::spot::{self, SpotRequestFailed, SpotRequestState, SpotRequestStatus};
+use crate::{Machine, MachineSetup, Market};
+
+/*
+ * Ec2Api is the subset of the EC2 API that Ec2Backend needs.
+ * It is implemented for rusoto's Ec2Client, and by fake::FakeEc2 so that the provisioning flow can run without AWS.
+ * Errors are expected to carry the AWS error code (e.g. "DependencyViolation") in their message.
+ */
+#[async_trait]
+pub trait Ec2Api: Send + Sync {
+    async fn create_security_group(&self, req: rusoto_ec2::CreateSecurityGroupRequest)
+        -> Result<rusoto_ec2::CreateSecurityGroupResult, Error>;
+    async fn authorize_security_group_ingress(&self, req: rusoto_ec2::AuthorizeSecurityGroupIngressRequest)
+        -> Result<(), Error>;
+    async fn create_key_pair(&self, req: rusoto_ec2::CreateKeyPairRequest)
+        -> Result<rusoto_ec2::KeyPair, Error>;
+    async fn import_key_pair(&self, req: rusoto_ec2::ImportKeyPairRequest)
+        -> Result<rusoto_ec2::ImportKeyPairResult, Error>;
+    async fn request_spot_instances(&self, req: rusoto_ec2::RequestSpotInstancesRequest)
+        -> Result<rusoto_ec2::RequestSpotInstancesResult, Error>;
+    async fn describe_spot_instance_requests(&self, req: rusoto_ec2::DescribeSpotInstanceRequestsRequest)
+        -> Result<rusoto_ec2::DescribeSpotInstanceRequestsResult, Error>;
+    async fn cancel_spot_instance_requests(&self, req: rusoto_ec2::CancelSpotInstanceRequestsRequest)
+        -> Result<rusoto_ec2::CancelSpotInstanceRequestsResult, Error>;
+    async fn describe_instances(&self, req: rusoto_ec2::DescribeInstancesRequest)
+        -> Result<rusoto_ec2::DescribeInstancesResult, Error>;
+    async fn terminate_instances(&self, req: rusoto_ec2::TerminateInstancesRequest)
+        -> Result<rusoto_ec2::TerminateInstancesResult, Error>;
+    async fn delete_security_group(&self, req: rusoto_ec2::DeleteSecurityGroupRequest)
+        -> Result<(), Error>;
+    async fn delete_key_pair(&self, req: rusoto_ec2::DeleteKeyPairRequest)
+        -> Result<(), Error>;
+    async fn describe_security_groups(&self, req: rusoto_ec2::DescribeSecurityGroupsRequest)
+        -> Result<rusoto_ec2::DescribeSecurityGroupsResult, Error>;
+    async fn describe_key_pairs(&self, req: rusoto_ec2::DescribeKeyPairsRequest)
+        -> Result<rusoto_ec2::DescribeKeyPairsResult, Error>;
+    async fn create_tags(&self, req: rusoto_ec2::CreateTagsRequest)
+        -> Result<(), Error>;
+    async fn modify_instance_attribute(&self, req: rusoto_ec2::ModifyInstanceAttributeRequest)
+        -> Result<(), Error>;
+    async fn run_instances(&self, req: rusoto_ec2::RunInstancesRequest)
+        -> Result<rusoto_ec2::Reservation, Error>;
+    async fn describe_availability_zones(&self, req: rusoto_ec2::DescribeAvailabilityZonesRequest)
+        -> Result<rusoto_ec2::DescribeAvailabilityZonesResult, Error>;
+}
+
+#[async_trait]
+impl Ec2Api for rusoto_ec2::Ec2Client {
+    async fn create_security_group(&self, req: rusoto_ec2::CreateSecurityGroupRequest)
+        -> Result<rusoto_ec2::CreateSecurityGroupResult, Error> {
+        Ok(Ec2::create_security_group(self, req).await?)
+    }
+
+    async fn authorize_security_group_ingress(&self, req: rusoto_ec2::AuthorizeSecurityGroupIngressRequest)
+        -> Result<(), Error> {
+        Ok(Ec2::authorize_security_group_ingress(self, req).await?)
+    }
+
+    async fn create_key_pair(&self, req: rusoto_ec2::CreateKeyPairRequest)
+        -> Result<rusoto_ec2::KeyPair, Error> {
+        Ok(Ec2::create_key_pair(self, req).await?)
+    }
+
+    async fn import_key_pair(&self, req: rusoto_ec2::ImportKeyPairRequest)
+        -> Result<rusoto_ec2::ImportKeyPairResult, Error> {
+        Ok(Ec2::import_key_pair(self, req).await?)
+    }
+
+    async fn request_spot_instances(&self, req: rusoto_ec2::RequestSpotInstancesRequest)
+        -> Result<rusoto_ec2::RequestSpotInstancesResult, Error> {
+        Ok(Ec2::request_spot_instances(self, req).await?)
+    }
+
+    async fn describe_spot_instance_requests(&self, req: rusoto_ec2::DescribeSpotInstanceRequestsRequest)
+        -> Result<rusoto_ec2::DescribeSpotInstanceRequestsResult, Error> {
+        Ok(Ec2::describe_spot_instance_requests(self, req).await?)
+    }
+
+    async fn cancel_spot_instance_requests(&self, req: rusoto_ec2::CancelSpotInstanceRequestsRequest)
+        -> Result<rusoto_ec2::CancelSpotInstanceRequestsResult, Error> {
+        Ok(Ec2::cancel_spot_instance_requests(self, req).await?)
+    }
+
+    async fn describe_instances(&self, req: rusoto_ec2::DescribeInstancesRequest)
+        -> Result<rusoto_ec2::DescribeInstancesResult, Error> {
+        Ok(Ec2::describe_instances(self, req).await?)
+    }
+
+    async fn terminate_instances(&self, req: rusoto_ec2::TerminateInstancesRequest)
+        -> Result<rusoto_ec2::TerminateInstancesResult, Error> {
+        Ok(Ec2::terminate_instances(self, req).await?)
+    }
+
+    async fn delete_security_group(&self, req: rusoto_ec2::DeleteSecurityGroupRequest)
+        -> Result<(), Error> {
+        Ok(Ec2::delete_security_group(self, req).await?)
+    }
+
+    async fn delete_key_pair(&self, req: rusoto_ec2::DeleteKeyPairRequest)
+        -> Result<(), Error> {
+        Ok(Ec2::delete_key_pair(self, req).await?)
+    }
+
+    async fn describe_security_groups(&self, req: rusoto_ec2::DescribeSecurityGroupsRequest)
+        -> Result<rusoto_ec2::DescribeSecurityGroupsResult, Error> {
+        Ok(Ec2::describe_security_groups(self, req).await?)
+    }
+
+    async fn describe_key_pairs(&self, req: rusoto_ec2::DescribeKeyPairsRequest)
+        -> Result<rusoto_ec2::DescribeKeyPairsResult, Error> {
+        Ok(Ec2::describe_key_pairs(self, req).await?)
+    }
+
+    async fn create_tags(&self, req: rusoto_ec2::CreateTagsRequest)
+        -> Result<(), Error> {
+        Ok(Ec2::create_tags(self, req).await?)
+    }
+
+    async fn modify_instance_attribute(&self, req: rusoto_ec2::ModifyInstanceAttributeRequest)
+        -> Result<(), Error> {
+        Ok(Ec2::modify_instance_attribute(self, req).await?)
+    }
+
+    async fn run_instances(&self, req: rusoto_ec2::RunInstancesRequest)
+        -> Result<rusoto_ec2::Reservation, Error> {
+        Ok(Ec2::run_instances(self, req).await?)
+    }
+
+    async fn describe_availability_zones(&self, req: rusoto_ec2::DescribeAvailabilityZonesRequest)
+        -> Result<rusoto_ec2::DescribeAvailabilityZonesResult, Error> {
+        Ok(Ec2::describe_availability_zones(self, req).await?)
+    }
+}
+
+/*
+ * Ec2Backend provisions machines as EC2 spot or on-demand instances, as the Market of each machine set says,
+ * through an Ec2Api client.
+ * Every resource it creates (security group, key pair, spot requests and instances) is recorded on the
+ * struct as soon as AWS hands back its id, so that teardown can clean up after a partial provision,
+ * and in the ledger if there is one, so that burst::reap can clean up after a crashed process.
+ * Every resource also carries the tags handed over with Backend::set_tags; spot requests and instances are
+ * additionally tagged with their machine set name, and instances with their index within the set.
+ * Instances terminate when shut down from the inside, and if a max duration is set they schedule that
+ * shutdown themselves at boot, so they stop billing even if this process never gets to tear them down.
+ * Provisioning fails with SpotRequestFailed as soon as a spot request of a Spot machine set will not launch its
+ * instance, unless retry_other_zones is set and the request ran out of capacity, in which case it is
+ * issued again in an availability zone not tried yet, or else with the next instance type the set accepts.
+ * Sets with preferred availability zones are placed in the first of them, and retried in the others in order
+ * when out of capacity, whether or not retry_other_zones is set.
+ * Spot sets with a Fulfillment instead wait for their requests until its timeout, and provisioning only fails
+ * if fewer than its min were fulfilled; on-demand launches ask AWS for at least min instances.
+ * Every wait on AWS, for spot requests, instances, termination or a deletable security group, is paced
+ * by backoff and fails with WaitTimeout once its deadline has passed; throttled requests are retried.
+ */
+pub struct Ec2Backend<C: Ec2Api = rusoto_ec2::Ec2Client> {
+    ec2: C,
+    region: String,
+    private_key_file: tempfile::NamedTempFile,
+    key: Key,
+    ledger: Option<Ledger>,
+    tags: Vec<(String, String)>,
+    max_duration: Option<Duration>,
+    ssh_user: String,
+    retry_other_zones: bool,
+    zones: Option<Vec<String>>,
+    security_groups: Vec<String>,
+    key_pairs: Vec<String>,
+    spot_requests: Vec<String>,
+    instances: Vec<String>,
+    backoff: Backoff,
+}
+
+/*
+ * Where the key pair of an Ec2Backend comes from, see KeyPair.
+ */
+enum Key {
+    Generated,
+    Imported { private_key: PathBuf, public_key: String },
+    Existing { name: String, private_key: Option<PathBuf> },
+}
+
+impl Ec2Backend {
+    /*
+     * Creates a backend talking to us-east-1 with credentials from the standard chain, see Credentials::default_chain.
+     */
+    pub fn new() -> Result<Self, Error> {
+        Self::in_region("us-east-1")
+    }
+
+    /*
+     * Creates a backend talking to the given region (e.g. "eu-west-1") with credentials from the standard chain.
+     */
+    pub fn in_region(region: &str) -> Result<Self, Error> {
+        Self::with_credentials(region, Credentials::default_chain()?)
+    }
+
+    /*
+     * Creates a backend talking to the given region with the given credentials.
+     */
+    pub fn with_credentials(region: &str, credentials: Credentials) -> Result<Self, Error> {
+        use rusoto_core::Region;
+
+        let region = region.parse::<Region>().context(format!("unknown aws region {}", region))?;
+        let ec2 = rusoto_ec2::Ec2Client::new_with(
+            rusoto_core::HttpClient::new()
+                .context("falied to create tls session for the ec2 api client")?,
+            credentials,
+            region.clone(),
+        );
+        Self::with_client(region.name(), ec2)
+    }
+}
+
+impl<C: Ec2Api> Ec2Backend<C> {
+    /*
+     * Creates a backend on top of any Ec2Api client talking to `region`, e.g. a fake::FakeEc2 in tests.
+     * The region is what the ledger records the backend's resources under.
+     */
+    pub fn with_client(region: &str, ec2: C) -> Result<Self, Error> {
+        Ok(Ec2Backend {
+            ec2,
+            region: region.to_string(),
+            private_key_file: tempfile::NamedTempFile::new()
+                .context("failed to create temporary file for key-pair")?,
+            key: Key::Generated,
+            ledger: None,
+            tags: Vec::new(),
+            max_duration: None,
+            ssh_user: "ec2-user".to_string(),
+            retry_other_zones: false,
+            zones: None,
+            security_groups: Vec::new(),
+            key_pairs: Vec::new(),
+            spot_requests: Vec::new(),
+            instances: Vec::new(),
+            backoff: Backoff::default(),
+        })
+    }
+
+    /*
+     * Sets how waits on AWS back off between checks and how long each may take, Backoff::default() by default.
+     */
+    pub fn set_backoff(&mut self, backoff: Backoff) {
+        self.backoff = backoff;
+    }
+
+    /*
+     * Makes the run's key pair an import of `public_key` (in OpenSSH format), which `private_key` is the
+     * private half of, instead of one created by AWS.
+     */
+    pub fn set_key(&mut self, private_key: &Path, public_key: &str) {
+        self.key = Key::Imported {
+            private_key: private_key.to_path_buf(),
+            public_key: public_key.trim().to_string(),
+        };
+    }
+
+    /*
+     * Sets the user ssh sessions log in as first, "ec2-user" (that of Amazon Linux) by default.
+     * Machine sets without a user of their own fall back on those of other common AMIs, see MachineSetup::set_ssh_user.
+     */
+    pub fn set_ssh_user(&mut self, user: &str) {
+        self.ssh_user = user.to_string();
+    }
+
+    /*
+     * Makes spot requests that ran out of capacity be retried in other availability zones of the region,
+     * instead of failing provisioning straight away.
+     */
+    pub fn set_retry_other_zones(&mut self, retry: bool) {
+        self.retry_other_zones = retry;
+    }
+
+    /*
+     * Records a resource in the ledger as soon as it is created; provisioning stops if that fails,
+     * since a resource the ledger does not know about could not be reaped after a crash.
+     */
+    fn created(&mut self, resource: Resource) -> Result<(), Error> {
+        match self.ledger {
+            Some(ref mut ledger) => ledger.created(&self.region, &resource),
+            None => Ok(()),
+        }
+    }
+
+    fn released(&mut self, log: &slog::Logger, resource: Resource) {
+        if let Some(ref mut ledger) = self.ledger {
+            if let Err(e) = ledger.released(&self.region, &resource) {
+                warn!(log, "{}", e);
+            }
+        }
+    }
+
+    /*
+     * The tags set with Backend::set_tags followed by `extra`.
+     */
+    fn tags(&self, extra: &[(&str, String)]) -> Vec<rusoto_ec2::Tag> {
+        self.tags
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.clone()))
+            .chain(extra.iter().cloned())
+            .map(|(key, value)| rusoto_ec2::Tag {
+                key: Some(key.to_string()),
+                value: Some(value),
+            })
+            .collect()
+    }
+
+    fn tag_specifications(&self, resource_type: &str, extra: &[(&str, String)]) -> Option<Vec<rusoto_ec2::TagSpecification>> {
+        Some(vec![rusoto_ec2::TagSpecification {
+            resource_type: Some(resource_type.to_string()),
+            tags: Some(self.tags(extra)),
+        }])
+    }
+
+    /*
+     * Creates a security group allowing ssh access from anywhere and any tcp traffic between the machines.
+     */
+    async fn create_security_group(&mut self, log: &slog::Logger) -> Result<String, Error> {
+        let group_name = random_name("burst_security_");
+        trace!(log, "creating a security group name"; "name" => &group_name);
+        let res = self.ec2.create_security_group(rusoto_ec2::CreateSecurityGroupRequest {
+            group_name,
+            description: "Temporary access groups for burst vms".to_string(),
+            tag_specifications: self.tag_specifications("security-group", &[]),
+            ..Default::default()
+        }).await.context("falied to create security groups for new machine")?;
+
+        let group_id = res.group_id.expect("aws created security group with no group id");
+        self.security_groups.push(group_id.clone());
+        self.created(Resource::SecurityGroup(group_id.clone()))?;
+        trace!(log, "created security group"; "id" => &group_id);
+
+        // Adding rules to security group for ssh access and intra-machine communication
+        trace!(log, "adding ssh access to security group");
+        self.ec2.authorize_security_group_ingress(rusoto_ec2::AuthorizeSecurityGroupIngressRequest {
+            group_id: Some(group_id.clone()),
+            ip_protocol: Some("tcp".to_string()),
+            from_port: Some(22),
+            to_port: Some(22),
+            cidr_ip: Some("0.0.0.0/0".to_string()),
+            ..Default::default()
+        }).await.context("falied to fill in security groups for new machine")?;
+
+        // Cross-VM Talk
+        trace!(log, "adding internal VM access to security group");
+        self.ec2.authorize_security_group_ingress(rusoto_ec2::AuthorizeSecurityGroupIngressRequest {
+            group_id: Some(group_id.clone()),
+            ip_protocol: Some("tcp".to_string()),
+            from_port: Some(0),
+            to_port: Some(65535),
+            cidr_ip: Some("172.31.0.0/16".to_string()),
+            ..Default::default()
+        }).await.context("falied to fill in security groups for new machine")?;
+
+        Ok(group_id)
+    }
+
+    /*
+     * Creates a key pair and saves the private key obtained to a temporary file for futhur usage like ssh,
+     * or imports the public key set with set_key, or checks that the existing key pair to use is there.
+     */
+    async fn create_key_pair(&mut self, log: &slog::Logger) -> Result<String, Error> {
+        if let Key::Existing { ref name, .. } = self.key {
+            trace!(log, "using existing keypair"; "name" => name);
+            let res = self.ec2.describe_key_pairs(rusoto_ec2::DescribeKeyPairsRequest {
+                key_names: Some(vec![name.clone()]),
+                ..Default::default()
+            }).await.context(format!("failed to look up key pair {}", name))?;
+            if res.key_pairs.unwrap_or_default().is_empty() {
+                return Err(failure::format_err!("key pair {} does not exist", name));
+            }
+            return Ok(name.clone());
+        }
+        trace!(log, "creating keypair");
+        let key_name = random_name("burst_key_");
+        if let Key::Imported { ref public_key, .. } = self.key {
+            let res = self.ec2.import_key_pair(rusoto_ec2::ImportKeyPairRequest {
+                key_name: key_name.clone(),
+                public_key_material: base64::encode(public_key).into(),
+                tag_specifications: self.tag_specifications("key-pair", &[]),
+                ..Default::default()
+            }).await.context("failed to import key pair")?;
+            self.key_pairs.push(key_name.clone());
+            self.created(Resource::KeyPair(key_name.clone()))?;
+            trace!(log, "imported keypair"; "fingerprint" => res.key_fingerprint);
+            return Ok(key_name);
+        }
+        let res = self.ec2.create_key_pair(rusoto_ec2::CreateKeyPairRequest {
+            key_name: key_name.clone(),
+            tag_specifications: self.tag_specifications("key-pair", &[]),
+            ..Default::default()
+        }).await.context("falied to generate new key pair")?;
+        self.key_pairs.push(key_name.clone());
+        self.created(Resource::KeyPair(key_name.clone()))?;
+        trace!(log, "created keypair"; "fingerprint" => res.key_fingerprint);
+
+        let private_key = res.key_material.expect("aws did not generate key material for new key");
+        self.private_key_file.write_all(private_key.as_bytes())
+            .context("could not write private key to the file")?;
+        if let Some(filename) = self.private_key_file.path().to_str() {
+            trace!(log, "wrote keypair to file"; "filename" => filename);
+        }
+
+        Ok(key_name)
+    }
+
+    /*
+     * Issues a spot request for `number` instances of the machine set `name` of the given instance type,
+     * in the given availability zone if any, records the request ids in `id_to_name` and returns them.
+     */
+    async fn request_spot_instances(
+        &mut self,
+        log: &slog::Logger,
+        (name, setup, number): (&str, &MachineSetup, u32),
+        instance_type: &str,
+        zone: Option<&str>,
+        access: &Access,
+        id_to_name: &mut HashMap<String, String>,
+    ) -> Result<Vec<String>, Error> {
+        let max_price = match *setup.market() {
+            Market::Spot { max_price } | Market::SpotThenOnDemand { max_price, .. } => max_price,
+            Market::OnDemand => None,
+        };
+        let req = rusoto_ec2::RequestSpotInstancesRequest {
+            instance_count: Some(i64::from(number)),
+            launch_specification: Some(rusoto_ec2::RequestSpotLaunchSpecification {
+                image_id: Some(setup.ami.clone()),
+                instance_type: Some(instance_type.to_string()),
+                security_group_ids: Some(vec![access.group_id.clone()]),
+                key_name: Some(access.key_name.clone()),
+                user_data: self.max_duration.map(shutdown_script),
+                placement: zone.map(|zone| rusoto_ec2::SpotPlacement {
+                    availability_zone: Some(zone.to_string()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            spot_price: max_price.map(|price| price.to_string()),
+            type_: Some("one-time".to_string()),
+            tag_specifications: self.tag_specifications("spot-instances-request", &[(SET_TAG, name.to_string())]),
+            ..Default::default()
+        };
+        trace!(log, "issuing spot request for {}", name; "#" => number, "instance_type" => instance_type, "zone" => zone);
+        let res = self.ec2.request_spot_instances(req).await
+            .context(format!("falied to request spot instance for {}", name))?;
+
+        let ids: Vec<String> = res.spot_instance_requests.unwrap_or_default()
+            .into_iter()
+            .filter_map(|sir| sir.spot_instance_request_id)
+            .collect();
+        for id in &ids {
+            trace!(log, "activated spot request"; "id" => id);
+            id_to_name.insert(id.clone(), name.to_string());
+            self.spot_requests.push(id.clone());
+            self.created(Resource::SpotRequest(id.clone()))?;
+        }
+        Ok(ids)
+    }
+
+    /*
+     * Waits until none of the issued spot requests is pending anymore, and records the instances of the satisfied
+     * ones in `launched`. Open requests of machine sets that have a deadline in `deadlines` are cancelled once
+     * it passed, and together with their unsatisfied requests counted in the returned number of machines each
+     * of those sets still lacks.
+     * Any other request that will not be satisfied fails provisioning with SpotRequestFailed straight away,
+     * or is retried in another availability zone if that is enabled and it ran out of capacity. Before failing,
+     * every request still open is cancelled, and the instances of the satisfied ones are recorded and tagged
+     * so that teardown still terminates them.
+     * The wait fails with WaitTimeout once the backoff deadline has passed since the latest of `deadlines`.
+     */
+    async fn wait_for_spot_requests(
+        &mut self,
+        log: &slog::Logger,
+        sets: &HashMap<String, (MachineSetup, u32)>,
+        access: &Access,
+        mut id_to_name: HashMap<String, String>,
+        deadlines: &HashMap<String, Instant>,
+        launched: &mut HashMap<String, (String, usize)>,
+    ) -> Result<HashMap<String, u32>, Error> {
+        if id_to_name.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let mut given_up = Vec::new();
+        // by spot request, the zones its instance type and the instance types before it were tried in
+        // by it and the requests it replaced
+        let mut tried: HashMap<String, (Vec<String>, Vec<String>)> = HashMap::new();
+        // the backoff deadline only starts counting once the last set's own deadline has passed
+        let mut backoff = self.backoff;
+        if let Some(latest) = deadlines.values().max() {
+            backoff.deadline += latest.saturating_duration_since(Instant::now());
+        }
+        let mut poll = Poll::new(backoff, "spot requests to be fulfilled");
+        debug!(log, "waiting for instances to spwan");
+        loop {
+            trace!(log, "checking spot request status");
+            let res = self.ec2.describe_spot_instance_requests(rusoto_ec2::DescribeSpotInstanceRequestsRequest {
+                spot_instance_request_ids: Some(id_to_name.keys().cloned().collect()),
+                ..Default::default()
+            }).await;
+            let res = match res {
+                Ok(res) => res,
+                Err(e) => {
+                    let msg = format!("{}", e);
+                    let waited = if msg.contains("The spot instance request ID") && msg.contains("does not exist") {
+                        trace!(log, "spot instance request not yet ready");
+                        poll.wait().await
+                    } else {
+                        poll.retry(e).await
+                    };
+                    if let Err(e) = waited {
+                        self.abandon_spot_requests(log, &id_to_name, launched).await?;
+                        return Err(e).context("falied to describe spot instances")?;
+                    }
+                    continue;
+                }
+            };
+
+            let statuses: Result<Vec<SpotRequestStatus>, Error> = res.spot_instance_requests.unwrap_or_default()
+                .into_iter()
+                .map(|sir| {
+                    let name = set_of(&id_to_name, &sir)?;
+                    SpotRequestStatus::from_aws(sir, &name)
+                })
+                .collect();
+            let statuses = match statuses {
+                Ok(statuses) => statuses,
+                Err(e) => {
+                    self.abandon_spot_requests(log, &id_to_name, launched).await?;
+                    return Err(e);
+                }
+            };
+
+            // instances are recorded as soon as they are launched, so that they are known to the ledger
+            // even if the process dies before the wait is over
+            let new: Vec<(String, String)> = statuses.iter()
+                .filter_map(|status| status.instance_id.clone().map(|instance_id| (instance_id, status.set.clone())))
+                .filter(|(instance_id, _)| !launched.contains_key(instance_id))
+                .collect();
+            if !new.is_empty() {
+                if let Err(e) = self.launched(log, new, launched).await {
+                    self.abandon_spot_requests(log, &id_to_name, launched).await?;
+                    return Err(e);
+                }
+            }
+
+            // requests that ran out of capacity are issued again wherever their set has alternatives left,
+            // before deadlines are looked at, so that sets with a deadline get them as well
+            let mut retried = false;
+            for status in &statuses {
+                let overdue = deadlines.get(&status.set).is_some_and(|deadline| Instant::now() >= *deadline);
+                if !status.is_out_of_capacity() || overdue {
+                    continue;
+                }
+                let (ref setup, _) = sets[&status.set];
+                let (zones_tried, types_tried) = tried_so_far(status, setup, &tried);
+                if let Some((instance_type, zone, zones_tried)) = self.alternative(log, status, setup, &zones_tried, &types_tried).await? {
+                    if status.state == SpotRequestState::Open {
+                        self.cancel_spot_request_ids(vec![status.id.clone()]).await
+                            .context("failed to cancel spot instance request")?;
+                    }
+                    id_to_name.remove(&status.id);
+                    tried.remove(&status.id);
+                    let ids = self.request_spot_instances(
+                        log, (&status.set, setup, 1), &instance_type, zone.as_deref(), access, &mut id_to_name,
+                    ).await?;
+                    for id in ids {
+                        tried.insert(id, (zones_tried.clone(), types_tried.clone()));
+                    }
+                    retried = true;
+                }
+            }
+            if retried {
+                continue;
+            }
+
+            let mut any_pending = false;
+            let mut give_up = Vec::new();
+            let mut failed = None;
+            for status in &statuses {
+                let deadline = deadlines.get(&status.set);
+                if status.is_fulfilled() {
+                    continue;
+                }
+                if status.is_pending() || (status.is_stuck() && deadline.is_some()) {
+                    trace!(log, "spot instance request not yet ready";
+                           "id" => &status.id, "state" => %status.state, "code" => &status.code);
+                    any_pending = true;
+                    let overdue = deadline.is_some_and(|deadline| Instant::now() >= *deadline);
+                    if status.state == SpotRequestState::Open && overdue && !given_up.contains(&status.id) {
+                        give_up.push(status.id.clone());
+                    }
+                } else if deadline.is_none() && failed.is_none() {
+                    failed = Some(status.clone());
+                }
+            }
+
+            if let Some(status) = failed {
+                let (ref setup, _) = sets[&status.set];
+                let (zones_tried, types_tried) = tried_so_far(&status, setup, &tried);
+                warn!(log, "spot request will not be satisfied";
+                      "set" => &status.set, "id" => &status.id, "state" => %status.state,
+                      "code" => &status.code, "message" => &status.message);
+                self.abandon_spot_requests(log, &id_to_name, launched).await?;
+                return Err(SpotRequestFailed {
+                    status,
+                    zones_tried: if self.retry_other_zones || setup.availability_zones().len() > 1 {
+                        zones_tried
+                    } else {
+                        Vec::new()
+                    },
+                    instance_types_tried: if setup.instance_types().len() > 1 { types_tried } else { Vec::new() },
+                }.into());
+            }
+
+            if !give_up.is_empty() {
+                info!(log, "giving up on spot requests that were not fulfilled in time"; "#" => give_up.len());
+                self.cancel_spot_request_ids(give_up.clone()).await
+                    .context("failed to cancel overdue spot instance requests")?;
+                given_up.extend(give_up);
+            }
+            if any_pending {
+                if let Err(e) = poll.wait().await {
+                    warn!(log, "spot requests were not fulfilled in time");
+                    self.abandon_spot_requests(log, &id_to_name, launched).await?;
+                    return Err(e);
+                }
+                continue;
+            }
+
+            let mut shortfall = HashMap::new();
+            for status in statuses {
+                match status.instance_id {
+                    // a request cancelled right as it was fulfilled still launched its instance
+                    Some(instance_id) => {
+                        trace!(log, "spot request satisfied"; "setup" => &status.set, "iid" => &instance_id);
+                    }
+                    None => {
+                        trace!(log, "spot request not satisfied"; "setup" => &status.set, "code" => &status.code);
+                        *shortfall.entry(status.set).or_insert(0) += 1;
+                    }
+                }
+            }
+            return Ok(shortfall);
+        }
+    }
+
+    /*
+     * Cancels the given spot requests and records the instances of those that launched one by now,
+     * so that none of them is left running unnoticed when provisioning gives up.
+     */
+    async fn abandon_spot_requests(
+        &mut self,
+        log: &slog::Logger,
+        id_to_name: &HashMap<String, String>,
+        launched: &mut HashMap<String, (String, usize)>,
+    ) -> Result<(), Error> {
+        let ids: Vec<String> = id_to_name.keys().cloned().collect();
+        self.cancel_spot_request_ids(ids.clone()).await
+            .context("falied to cancel spot instance requests")?;
+        let res = self.ec2.describe_spot_instance_requests(rusoto_ec2::DescribeSpotInstanceRequestsRequest {
+            spot_instance_request_ids: Some(ids),
+            ..Default::default()
+        }).await.context("falied to describe spot instances")?;
+        let mut fulfilled = Vec::new();
+        for sir in res.spot_instance_requests.unwrap_or_default() {
+            let name = set_of(id_to_name, &sir)?;
+            if let Some(instance_id) = sir.instance_id {
+                fulfilled.push((instance_id, name));
+            }
+        }
+        self.launched(log, fulfilled, launched).await
+    }
+
+    /*
+     * Where to issue a spot request of the machine set again that ran out of capacity, after it and the requests
+     * it replaced were tried in `zones_tried` with its instance type, and with the instance types in `types_tried`:
+     * the same instance type in the next availability zone, or else the next instance type in the set's preferred zone.
+     * Returns the instance type, the zone and the zones the new request counts as tried in already.
+     */
+    async fn alternative(
+        &mut self,
+        log: &slog::Logger,
+        status: &SpotRequestStatus,
+        setup: &MachineSetup,
+        zones_tried: &[String],
+        types_tried: &[String],
+    ) -> Result<Option<(String, Option<String>, Vec<String>)>, Error> {
+        if let Some(zone) = self.next_zone(setup, zones_tried).await? {
+            info!(log, "retrying spot request in another availability zone";
+                  "set" => &status.set, "code" => &status.code, "zone" => &zone);
+            let instance_type = status.instance_type.clone().unwrap_or_else(|| setup.instance_type().to_string());
+            return Ok(Some((instance_type, Some(zone), zones_tried.to_vec())));
+        }
+        if let Some(next) = setup.instance_types().iter().find(|t| !types_tried.contains(t)) {
+            info!(log, "retrying spot request with another instance type";
+                  "set" => &status.set, "code" => &status.code, "instance_type" => next);
+            return Ok(Some((next.clone(), setup.availability_zones().first().cloned(), Vec::new())));
+        }
+        Ok(None)
+    }
+
+    /*
+     * The availability zone to retry a spot request of the machine set in after those in `tried` had no capacity:
+     * the next one the set prefers, or if it has none left and retry_other_zones is set, any other of the region.
+     */
+    async fn next_zone(&mut self, setup: &MachineSetup, tried: &[String]) -> Result<Option<String>, Error> {
+        if let Some(zone) = setup.availability_zones().iter().find(|zone| !tried.contains(zone)) {
+            return Ok(Some(zone.clone()));
+        }
+        if !self.retry_other_zones {
+            return Ok(None);
+        }
+        Ok(self.zones().await?.into_iter().find(|zone| !tried.contains(zone)))
+    }
+
+    /*
+     * The availability zones of the region that are available, looked up once.
+     */
+    async fn zones(&mut self) -> Result<Vec<String>, Error> {
+        if self.zones.is_none() {
+            let res = self.ec2.describe_availability_zones(rusoto_ec2::DescribeAvailabilityZonesRequest {
+                filters: Some(vec![rusoto_ec2::Filter {
+                    name: Some("state".to_string()),
+                    values: Some(vec!["available".to_string()]),
+                }]),
+                ..Default::default()
+            }).await.context("failed to look up availability zones")?;
+            self.zones = Some(res.availability_zones.unwrap_or_default()
+                .into_iter()
+                .filter_map(|zone| zone.zone_name)
+                .collect());
+        }
+        Ok(self.zones.clone().unwrap_or_default())
+    }
+
+    /*
+     * Launches `number` on-demand instances for the machine set `name` and records them in `launched`,
+     * going down its instance types, and for each its preferred availability zones, for whatever the ones
+     * before had no capacity for. Fails unless at least
+     * `min` instances were launched.
+     */
+    async fn run_instances(
+        &mut self,
+        log: &slog::Logger,
+        (name, setup, number): (&str, &MachineSetup, u32),
+        mut min: u32,
+        access: &Access,
+        launched: &mut HashMap<String, (String, usize)>,
+    ) -> Result<(), Error> {
+        let mut remaining = number;
+        let zones: Vec<Option<&str>> = match setup.availability_zones() {
+            [] => vec![None],
+            zones => zones.iter().map(|zone| Some(zone.as_str())).collect(),
+        };
+        let candidates: Vec<(&String, Option<&str>)> = setup.instance_types()
+            .iter()
+            .flat_map(|instance_type| zones.iter().map(move |&zone| (instance_type, zone)))
+            .collect();
+        for (i, &(instance_type, zone)) in candidates.iter().enumerate() {
+            if remaining == 0 {
+                break;
+            }
+            let last = i + 1 == candidates.len();
+            trace!(log, "launching on-demand instances for {}", name;
+                   "#" => remaining, "min" => min, "instance_type" => instance_type, "zone" => zone);
+            let res = self.ec2.run_instances(rusoto_ec2::RunInstancesRequest {
+                image_id: Some(setup.ami.clone()),
+                instance_type: Some(instance_type.clone()),
+                placement: zone.map(|zone| rusoto_ec2::Placement {
+                    availability_zone: Some(zone.to_string()),
+                    ..Default::default()
+                }),
+                // AWS launches at least one instance or none at all; the minimum is left to the last candidate
+                min_count: i64::from(if last { min.max(1) } else { 1 }),
+                max_count: i64::from(remaining),
+                security_group_ids: Some(vec![access.group_id.clone()]),
+                key_name: Some(access.key_name.clone()),
+                user_data: self.max_duration.map(shutdown_script),
+                instance_initiated_shutdown_behavior: Some("terminate".to_string()),
+                tag_specifications: self.tag_specifications("instance", &[(SET_TAG, name.to_string())]),
+                ..Default::default()
+            }).await;
+            let res = match res {
+                Err(ref e) if format!("{}", e).contains("InsufficientInstanceCapacity") && (!last || min == 0) => {
+                    warn!(log, "no on-demand capacity left"; "set" => name, "instance_type" => instance_type, "zone" => zone);
+                    continue;
+                }
+                res => res.context(format!("failed to launch on-demand instances for {}", name))?,
+            };
+
+            let instances: Vec<(String, String)> = res.instances.unwrap_or_default()
+                .into_iter()
+                .filter_map(|i| i.instance_id)
+                .map(|instance_id| (instance_id, name.to_string()))
+                .collect();
+            remaining -= instances.len() as u32;
+            min = min.saturating_sub(instances.len() as u32);
+            self.launched(log, instances, launched).await?;
+        }
+        Ok(())
+    }
+
+    /*
+     * Records newly launched instances, given as instance id and machine set name, so that teardown terminates them,
+     * gives each the next free index in its set, prepares them and adds them to `launched`.
+     * Instances already in `launched` are skipped.
+     */
+    async fn launched(
+        &mut self,
+        log: &slog::Logger,
+        instances: Vec<(String, String)>,
+        launched: &mut HashMap<String, (String, usize)>,
+    ) -> Result<(), Error> {
+        let instances: Vec<(String, String)> = instances
+            .into_iter()
+            .filter(|(instance_id, _)| !launched.contains_key(instance_id))
+            .collect();
+        self.instances.extend(instances.iter().map(|(instance_id, _)| instance_id.clone()));
+        let mut new: HashMap<String, (String, usize)> = HashMap::new();
+        for (instance_id, name) in instances {
+            self.created(Resource::Instance(instance_id.clone()))?;
+            let index = launched.values().chain(new.values()).filter(|(n, _)| *n == name).count();
+            new.insert(instance_id, (name, index));
+        }
+        let res = self.prepare_instances(log, &new).await;
+        launched.extend(new);
+        res
+    }
+
+    /*
+     * Here once all the ec2 spot instance requests are satified, the instances are now starting or runing.
+     * The spot instance requests are cancelled, to ensure that if anyone of the instances stops, the spot instance requests are not called again.
+     * All the requests happen once and all the instances are requested/started only once.
+     */
+    async fn cancel_spot_requests(&mut self, log: &slog::Logger) -> Result<(), Error> {
+        trace!(log, "terminating spot requests");
+        let ids = std::mem::take(&mut self.spot_requests);
+        match self.cancel_spot_request_ids(ids.clone()).await {
+            Ok(()) => {}
+            // a request that no longer exists needs no cancelling, but one of them fails the whole call
+            Err(ref e) if format!("{}", e).contains("InvalidSpotInstanceRequestID.NotFound") && ids.len() > 1 => {
+                let mut failed = None;
+                for id in ids {
+                    match self.cancel_spot_request_ids(vec![id.clone()]).await {
+                        Ok(()) => self.released(log, Resource::SpotRequest(id)),
+                        Err(ref e) if format!("{}", e).contains("InvalidSpotInstanceRequestID.NotFound") => {
+                            self.released(log, Resource::SpotRequest(id))
+                        }
+                        Err(e) => {
+                            warn!(log, "failed to cancel sopt instance request {}: {:?}", id, e);
+                            self.spot_requests.push(id);
+                            failed = Some(e);
+                        }
+                    }
+                }
+                return match failed {
+                    Some(e) => Err(e).context("falied to cancel spot instance request")?,
+                    None => Ok(()),
+                };
+            }
+            Err(ref e) if format!("{}", e).contains("InvalidSpotInstanceRequestID.NotFound") => {}
+            Err(e) => {
+                warn!(log, "failed to cancel sopt instance requests: {:?}", e);
+                self.spot_requests = ids;
+                return Err(e).context("falied to cancel spot instance request")?;
+            }
+        }
+        for id in ids {
+            self.released(log, Resource::SpotRequest(id));
+        }
+        Ok(())
+    }
+
+    async fn cancel_spot_request_ids(&self, ids: Vec<String>) -> Result<(), Error> {
+        self.ec2.cancel_spot_instance_requests(rusoto_ec2::CancelSpotInstanceRequestsRequest {
+            spot_instance_request_ids: ids,
+            ..Default::default()
+        }).await?;
+        Ok(())
+    }
+
+    /*
+     * Lastly ec2 remote instance termination request is executed to stop all the instances started.
+     * Instances that turn out not to exist anymore are forgotten about.
+     */
+    async fn terminate_instances(&mut self, log: &slog::Logger) -> Result<(), Error> {
+        debug!(log, "terminating instances");
+        match self.terminate_instance_ids(log, self.instances.clone()).await {
+            Ok(()) => Ok(()),
+            // an instance that no longer exists needs no terminating, but one of them fails the whole call
+            Err(ref e) if format!("{}", e).contains("InvalidInstanceID.NotFound") => {
+                let mut failed = None;
+                for id in std::mem::take(&mut self.instances) {
+                    match self.terminate_instance_ids(log, vec![id.clone()]).await {
+                        Ok(()) => self.instances.push(id),
+                        Err(ref e) if format!("{}", e).contains("InvalidInstanceID.NotFound") => {
+                            self.released(log, Resource::Instance(id))
+                        }
+                        Err(e) => {
+                            self.instances.push(id);
+                            failed = Some(e);
+                        }
+                    }
+                }
+                match failed {
+                    Some(e) => Err(e).context("failed to terminate instances")?,
+                    None => Ok(()),
+                }
+            }
+            Err(e) => Err(e).context("failed to terminate instances")?,
+        }
+    }
+
+    async fn terminate_instance_ids(&self, log: &slog::Logger, ids: Vec<String>) -> Result<(), Error> {
+        let termination_req = rusoto_ec2::TerminateInstancesRequest {
+            instance_ids: ids,
+            ..Default::default()
+        };
+        let mut poll = Poll::new(self.backoff, "instance termination to be accepted");
+        while let Err(e) = self.ec2.terminate_instances(termination_req.clone()).await {
+            trace!(log, "retrying instance termination");
+            poll.retry(e).await?;
+        }
+        Ok(())
+    }
+
+    /*
+     * Waits until every instance is terminated, forgetting about each one as soon as it is.
+     * The security group can only be deleted once no instance uses it anymore.
+     */
+    async fn wait_for_termination(&mut self, log: &slog::Logger) -> Result<(), Error> {
+        let mut poll = Poll::new(self.backoff, "instances to terminate");
+        while !self.instances.is_empty() {
+            let res = self.ec2.describe_instances(rusoto_ec2::DescribeInstancesRequest {
+                instance_ids: Some(self.instances.clone()),
+                ..Default::default()
+            }).await;
+            match res {
+                Ok(res) => {
+                    let terminated: Vec<String> = res.reservations.unwrap_or_default()
+                        .into_iter()
+                        .flat_map(|r| r.instances.unwrap_or_default())
+                        .filter(|i| i.state.as_ref().and_then(|s| s.name.as_deref()) == Some("terminated"))
+                        .filter_map(|i| i.instance_id)
+                        .collect();
+                    self.instances.retain(|id| !terminated.contains(id));
+                    for id in terminated {
+                        self.released(log, Resource::Instance(id));
+                    }
+                }
+                Err(e) => {
+                    poll.retry(e).await.context("failed to describe terminating instances")?;
+                    continue;
+                }
+            }
+            if self.instances.is_empty() {
+                break;
+            }
+            trace!(log, "waiting for instances to terminate"; "#" => self.instances.len());
+            poll.wait().await.context(format!("{} instances did not terminate in time", self.instances.len()))?;
+        }
+        Ok(())
+    }
+
+    /*
+     * Deletes the security group, retrying while AWS still considers it in use (DependencyViolation),
+     * which happens for a while after the last instance in it terminated.
+     */
+    async fn delete_security_group(&mut self, log: &slog::Logger, group_id: String) -> Result<(), Error> {
+        trace!(log, "cleaning up terminating security group"; "id" => &group_id);
+        let mut poll = Poll::new(self.backoff, "security group to be released");
+        let req = rusoto_ec2::DeleteSecurityGroupRequest {
+            group_id: Some(group_id.clone()),
+            ..Default::default()
+        };
+        loop {
+            match self.ec2.delete_security_group(req.clone()).await {
+                Ok(()) => break,
+                Err(e) if format!("{}", e).contains("InvalidGroup.NotFound") => break,
+                Err(e) if format!("{}", e).contains("DependencyViolation") => {
+                    trace!(log, "security group still in use");
+                    poll.wait().await.context("failed to clean secuity group")?;
+                }
+                Err(e) => poll.retry(e).await.context("failed to clean secuity group")?,
+            }
+        }
+        self.released(log, Resource::SecurityGroup(group_id));
+        Ok(())
+    }
+
+    async fn delete_key_pair(&mut self, log: &slog::Logger, key_name: String) -> Result<(), Error> {
+        trace!(log, "cleaning up terminating keypair"; "name" => &key_name);
+        let req = rusoto_ec2::DeleteKeyPairRequest {
+            key_name: Some(key_name.clone()),
+            ..Default::default()
+        };
+        let mut poll = Poll::new(self.backoff, "key pair to be deleted");
+        while let Err(e) = self.ec2.delete_key_pair(req.clone()).await {
+            poll.retry(e).await.context("failed to clean key pair")?;
+        }
+        self.released(log, Resource::KeyPair(key_name));
+        Ok(())
+    }
+
+    /*
+     * Destroys resources left behind by an earlier run, e.g. the outstanding entries of a ledger,
+     * and returns the ones that could not be destroyed.
+     * The instances launched by spot requests are terminated too, whether or not they were recorded,
+     * since a run killed while waiting on its spot requests may not have gotten to record them.
+     */
+    pub async fn reap(&mut self, log: &slog::Logger, resources: Vec<Resource>) -> Vec<Resource> {
+        let mut leftovers = Vec::new();
+        for resource in resources {
+            info!(log, "reaping {}", resource);
+            match resource {
+                Resource::Instance(id) => {
+                    if !self.instances.contains(&id) {
+                        self.instances.push(id);
+                    }
+                }
+                Resource::SpotRequest(id) => match self.spot_request_instance(log, &id).await {
+                    Ok(instance) => {
+                        if let Some(instance_id) = instance.filter(|instance_id| !self.instances.contains(instance_id)) {
+                            debug!(log, "spot request launched an instance"; "id" => &id, "iid" => &instance_id);
+                            if let Err(e) = self.created(Resource::Instance(instance_id.clone())) {
+                                warn!(log, "{}", e);
+                            }
+                            self.instances.push(instance_id);
+                        }
+                        self.spot_requests.push(id);
+                    }
+                    Err(e) => {
+                        warn!(log, "failed to look up spot request {}: {}", id, e);
+                        leftovers.push(Resource::SpotRequest(id));
+                    }
+                },
+                Resource::SecurityGroup(id) => self.security_groups.push(id),
+                Resource::KeyPair(name) => self.key_pairs.push(name),
+                resource => leftovers.push(resource),
+            }
+        }
+        leftovers.extend(self.teardown(log).await);
+        leftovers
+    }
+
+    /*
+     * The instance the spot request `id` launched, if any. The request is cancelled first, so that it cannot
+     * launch one after it was looked at; a cancelled request still names the instance it launched.
+     */
+    async fn spot_request_instance(&self, log: &slog::Logger, id: &str) -> Result<Option<String>, Error> {
+        match self.cancel_spot_request_ids(vec![id.to_string()]).await {
+            Ok(()) => {}
+            Err(ref e) if format!("{}", e).contains("InvalidSpotInstanceRequestID.NotFound") => return Ok(None),
+            Err(e) => return Err(e).context("failed to cancel spot instance request")?,
+        }
+        let req = rusoto_ec2::DescribeSpotInstanceRequestsRequest {
+            spot_instance_request_ids: Some(vec![id.to_string()]),
+            ..Default::default()
+        };
+        let mut poll = Poll::new(self.backoff, "spot request to be described");
+        loop {
+            match self.ec2.describe_spot_instance_requests(req.clone()).await {
+                Ok(res) => {
+                    return Ok(res.spot_instance_requests.unwrap_or_default()
+                        .into_iter()
+                        .find_map(|sir| sir.instance_id));
+                }
+                Err(e) if format!("{}", e).contains("InvalidSpotInstanceRequestID.NotFound") => {
+                    trace!(log, "spot instance request not yet known"; "id" => id);
+                    poll.wait().await
+                }
+                Err(e) => poll.retry(e).await,
+            }.context("failed to describe spot instances")?;
+        }
+    }
+
+    /*
+     * Finds every live resource carrying the tag `key` with the given value, so that it can be reaped.
+     */
+    pub async fn find_tagged(&self, key: &str, value: &str) -> Result<Vec<Resource>, Error> {
+        let tag = |extra: Option<rusoto_ec2::Filter>| {
+            let mut filters = vec![rusoto_ec2::Filter {
+                name: Some(format!("tag:{}", key)),
+                values: Some(vec![value.to_string()]),
+            }];
+            filters.extend(extra);
+            Some(filters)
+        };
+        let mut resources = Vec::new();
+
+        let res = self.ec2.describe_spot_instance_requests(rusoto_ec2::DescribeSpotInstanceRequestsRequest {
+            filters: tag(Some(rusoto_ec2::Filter {
+                name: Some("state".to_string()),
+                values: Some(vec!["open".to_string(), "active".to_string()]),
+            })),
+            ..Default::default()
+        }).await.context("failed to look up tagged spot requests")?;
+        resources.extend(res.spot_instance_requests.unwrap_or_default()
+            .into_iter()
+            .filter_map(|sir| sir.spot_instance_request_id)
+            .map(Resource::SpotRequest));
+
+        let res = self.ec2.describe_instances(rusoto_ec2::DescribeInstancesRequest {
+            filters: tag(Some(rusoto_ec2::Filter {
+                name: Some("instance-state-name".to_string()),
+                values: Some(["pending", "running", "shutting-down", "stopping", "stopped"]
+                    .iter().map(|s| s.to_string()).collect()),
+            })),
+            ..Default::default()
+        }).await.context("failed to look up tagged instances")?;
+        resources.extend(res.reservations.unwrap_or_default()
+            .into_iter()
+            .flat_map(|r| r.instances.unwrap_or_default())
+            .filter_map(|i| i.instance_id)
+            .map(Resource::Instance));
+
+        let res = self.ec2.describe_security_groups(rusoto_ec2::DescribeSecurityGroupsRequest {
+            filters: tag(None),
+            ..Default::default()
+        }).await.context("failed to look up tagged security groups")?;
+        resources.extend(res.security_groups.unwrap_or_default()
+            .into_iter()
+            .filter_map(|g| g.group_id)
+            .map(Resource::SecurityGroup));
+
+        let res = self.ec2.describe_key_pairs(rusoto_ec2::DescribeKeyPairsRequest {
+            filters: tag(None),
+            ..Default::default()
+        }).await.context("failed to look up tagged key pairs")?;
+        resources.extend(res.key_pairs.unwrap_or_default()
+            .into_iter()
+            .filter_map(|k| k.key_name)
+            .map(Resource::KeyPair));
+
+        Ok(resources)
+    }
+
+    /*
+     * Tags every instance with its machine set name and index, since the tags of a spot request are not
+     * passed on to the instance it launches, and makes it terminate rather than stop when it shuts itself down.
+     * A new instance may not be known to CreateTags and ModifyInstanceAttribute straight away.
+     */
+    async fn prepare_instances(&self, log: &slog::Logger, instances: &HashMap<String, (String, usize)>) -> Result<(), Error> {
+        trace!(log, "tagging instances");
+        for (instance_id, (name, index)) in instances {
+            let req = rusoto_ec2::CreateTagsRequest {
+                resources: vec![instance_id.clone()],
+                tags: self.tags(&[(SET_TAG, name.clone()), (INDEX_TAG, index.to_string())]),
+                ..Default::default()
+            };
+            let mut poll = Poll::new(self.backoff, "instance to be known to CreateTags");
+            loop {
+                match self.ec2.create_tags(req.clone()).await {
+                    Ok(()) => break,
+                    Err(e) if format!("{}", e).contains("InvalidInstanceID.NotFound") => {
+                        trace!(log, "instance not yet known to tag"; "iid" => instance_id);
+                        poll.wait().await
+                    }
+                    Err(e) => poll.retry(e).await,
+                }.context(format!("failed to tag instance {}", instance_id))?;
+            }
+
+            let req = rusoto_ec2::ModifyInstanceAttributeRequest {
+                instance_id: instance_id.clone(),
+                instance_initiated_shutdown_behavior: Some(rusoto_ec2::AttributeValue {
+                    value: Some("terminate".to_string()),
+                }),
+                ..Default::default()
+            };
+            let mut poll = Poll::new(self.backoff, "instance to be known to ModifyInstanceAttribute");
+            loop {
+                match self.ec2.modify_instance_attribute(req.clone()).await {
+                    Ok(()) => break,
+                    Err(e) if format!("{}", e).contains("InvalidInstanceID.NotFound") => {
+                        trace!(log, "instance not yet known to modify"; "iid" => instance_id);
+                        poll.wait().await
+                    }
+                    // one-time spot instances are terminated on shutdown anyway, whether or not the attribute can be set
+                    Err(e) if format!("{}", e).contains("UnsupportedOperation") => {
+                        debug!(log, "instance keeps its shutdown behavior: {}", e; "iid" => instance_id);
+                        break;
+                    }
+                    Err(e) => poll.retry(e).await,
+                }.context(format!("failed to set shutdown behavior of instance {}", instance_id))?;
+            }
+        }
+        Ok(())
+    }
+
+    /*
+     * Polls the given instances until every one of them has its addresses assigned,
+     * and returns them as Machines grouped by machine set name, each set ordered by machine index.
+     */
+    async fn wait_for_instances(
+        &self,
+        log: &slog::Logger,
+        id_to_name: &HashMap<String, (String, usize)>,
+    ) -> Result<HashMap<String, Vec<Machine>>, Error> {
+        let desc_req = rusoto_ec2::DescribeInstancesRequest {
+            instance_ids: Some(id_to_name.keys().cloned().collect()),
+            ..Default::default()
+        };
+        let mut poll = Poll::new(self.backoff, "instances to get their addresses");
+        let mut machines: HashMap<String, Vec<(usize, Machine)>> = HashMap::new();
+        let mut all_ready = false;
+        while !all_ready {
+            machines.clear();
+            let res = match self.ec2.describe_instances(desc_req.clone()).await {
+                Ok(res) => res,
+                Err(e) => {
+                    poll.retry(e).await.context("failed to describe spot instances")?;
+                    continue;
+                }
+            };
+            all_ready = true;
+            for reservation in res.reservations.unwrap_or_default() {
+                for instance in reservation.instances.unwrap_or_default() {
+                    match instance {
+                        rusoto_ec2::Instance {
+                            instance_id: Some(instance_id),
+                            instance_type: Some(instance_type),
+                            private_ip_address: Some(private_ip),
+                            public_dns_name: Some(public_dns),
+                            public_ip_address: Some(public_ip),
+                            placement,
+                            ..
+                        } => {
+                            let machine = Machine {
+                                ssh: None,
+                                ssh_user: "ec2-user".to_string(),
+                                instance_type,
+                                availability_zone: placement.and_then(|p| p.availability_zone).unwrap_or_default(),
+                                private_ip,
+                                public_dns,
+                                public_ip,
+                                ssh_port: 22,
+                            };
+                            let (ref name, index) = id_to_name[&instance_id];
+                            trace!(log, "instance ready"; "set" => name, "index" => index, "ip" => &machine.public_ip);
+                            machines.entry(name.clone()).or_default().push((index, machine));
+                        }
+                        _ => {
+                            all_ready = false;
+                        }
+                    }
+                }
+            }
+            if !all_ready {
+                trace!(log, "waiting for instances to get their addresses");
+                poll.wait().await?;
+            }
+        }
+        Ok(machines
+            .into_iter()
+            .map(|(name, mut machines)| {
+                machines.sort_by_key(|&(index, _)| index);
+                (name, machines.into_iter().map(|(_, machine)| machine).collect())
+            })
+            .collect())
+    }
+
+    /*
+     * Provisions the machine sets among `sets` that are named in `names`, see Backend::provision.
+     */
+    pub(crate) async fn provision_sets(
+        &mut self,
+        log: &slog::Logger,
+        sets: &HashMap<String, (MachineSetup, u32)>,
+        names: &[&str],
+    ) -> Result<HashMap<String, Vec<Machine>>, Error> {
+        let group_id = self.create_security_group(log).await?;
+        let key_name = self.create_key_pair(log).await?;
+
+        let access = Access { group_id, key_name };
+
+        /*
+         * Here we are launching the on-demand machine sets straight away, and requesting spot instances for
+         * all the other machine sets and recording the request ids.
+         */
+        let mut launched = HashMap::new();
+        let mut id_to_name = HashMap::new();
+        let mut deadlines = HashMap::new();
+        debug!(log, "issuing spot requests");
+        let in_scope = |name: &String| names.contains(&name.as_str());
+        for (name, (setup, number)) in sets.iter().filter(|(name, _)| in_scope(name)) {
+            match *setup.market() {
+                Market::OnDemand => {
+                    let min = setup.fulfillment().map_or(*number, |f| f.min.min(*number));
+                    self.run_instances(log, (name, setup, *number), min, &access, &mut launched).await?;
+                    continue;
+                }
+                Market::Spot { .. } => {
+                    if let Some(fulfillment) = setup.fulfillment() {
+                        deadlines.insert(name.clone(), Instant::now() + fulfillment.timeout);
+                    }
+                }
+                Market::SpotThenOnDemand { timeout, .. } => {
+                    deadlines.insert(name.clone(), Instant::now() + timeout);
+                }
+            }
+            let zone = setup.availability_zones().first().map(String::as_str);
+            self.request_spot_instances(log, (name, setup, *number), setup.instance_type(), zone, &access, &mut id_to_name).await?;
+        }
+
+        let shortfall = self.wait_for_spot_requests(log, sets, &access, id_to_name, &deadlines, &mut launched).await;
+        if !self.spot_requests.is_empty() {
+            self.cancel_spot_requests(log).await?;
+        }
+        for (name, missing) in shortfall? {
+            let (ref setup, number) = sets[&name];
+            if let Market::SpotThenOnDemand { .. } = *setup.market() {
+                info!(log, "falling back to on-demand instances"; "set" => &name, "#" => missing);
+                let min = setup.fulfillment().map_or(number, |f| f.min.min(number)).saturating_sub(number - missing);
+                self.run_instances(log, (&name, setup, missing), min, &access, &mut launched).await?;
+            }
+        }
+
+        for (name, (setup, number)) in sets.iter().filter(|(name, _)| in_scope(name)) {
+            let obtained = launched.values().filter(|(n, _)| n == name).count() as u32;
+            let min = setup.fulfillment().map_or(*number, |f| f.min.min(*number));
+            if obtained < min {
+                return Err(InsufficientMachines { set: name.clone(), wanted: *number, min, obtained }.into());
+            }
+            if obtained < *number {
+                warn!(log, "carrying on with fewer machines than asked for"; "set" => name, "#" => obtained, "wanted" => number);
+            }
+        }
+        self.wait_for_instances(log, &launched).await
+    }
+
+    /*
+     * Lets the machines at the given public ips, e.g. those of the run in other regions, reach the machines
+     * of this backend on any tcp port.
+     */
+    pub(crate) async fn allow_ingress(&mut self, log: &slog::Logger, ips: &[String]) -> Result<(), Error> {
+        let group_id = match self.security_groups.last() {
+            Some(group_id) => group_id.clone(),
+            None => return Ok(()),
+        };
+        if ips.is_empty() {
+            return Ok(());
+        }
+        trace!(log, "adding access from other regions to security group"; "#" => ips.len());
+        self.ec2.authorize_security_group_ingress(rusoto_ec2::AuthorizeSecurityGroupIngressRequest {
+            group_id: Some(group_id),
+            ip_permissions: Some(vec![rusoto_ec2::IpPermission {
+                ip_protocol: Some("tcp".to_string()),
+                from_port: Some(0),
+                to_port: Some(65535),
+                ip_ranges: Some(ips.iter().map(|ip| rusoto_ec2::IpRange {
+                    cidr_ip: Some(format!("{}/32", ip)),
+                    ..Default::default()
+                }).collect()),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        }).await.context("failed to let other regions into the security group")?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<C: Ec2Api> Backend for Ec2Backend<C> {
+    async fn provision(
+        &mut self,
+        log: &slog::Logger,
+        sets: &HashMap<String, (MachineSetup, u32)>,
+    ) -> Result<HashMap<String, Vec<Machine>>, Error> {
+        for (name, (setup, _)) in sets {
+            if let Some(other) = setup.region().filter(|&other| other != self.region) {
+                return Err(failure::format_err!(
+                    "machine set {} is placed in {}, but the backend provisions in {}", name, other, self.region
+                ));
+            }
+        }
+        let names: Vec<&str> = sets.keys().map(String::as_str).collect();
+        self.provision_sets(log, sets, &names).await
+    }
+
+    fn private_key(&self) -> Option<&Path> {
+        match self.key {
+            Key::Generated => Some(self.private_key_file.path()),
+            Key::Imported { ref private_key, .. } => Some(private_key),
+            Key::Existing { ref private_key, .. } => private_key.as_deref(),
+        }
+    }
+
+    fn ssh_user(&self) -> &str {
+        &self.ssh_user
+    }
+
+    async fn teardown(&mut self, log: &slog::Logger) -> Vec<Resource> {
+        if !self.spot_requests.is_empty() {
+            // failures are logged by cancel_spot_requests, the requests end up in the leftovers
+            let _ = self.cancel_spot_requests(log).await;
+        }
+
+        if !self.instances.is_empty() {
+            let terminated = match self.terminate_instances(log).await {
+                Ok(()) => self.wait_for_termination(log).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = terminated {
+                warn!(log, "failed to terminate instances: {}", e);
+            }
+        }
+
+        debug!(log, "cleaning up temporary resources");
+        if self.instances.is_empty() {
+            for group_id in std::mem::take(&mut self.security_groups) {
+                if let Err(e) = self.delete_security_group(log, group_id.clone()).await {
+                    warn!(log, "{}", e);
+                    self.security_groups.push(group_id);
+                }
+            }
+        }
+        for key_name in std::mem::take(&mut self.key_pairs) {
+            if let Err(e) = self.delete_key_pair(log, key_name.clone()).await {
+                warn!(log, "{}", e);
+                self.key_pairs.push(key_name);
+            }
+        }
+
+        let mut leftovers: Vec<Resource> = Vec::new();
+        leftovers.extend(self.spot_requests.iter().cloned().map(Resource::SpotRequest));
+        leftovers.extend(self.instances.iter().cloned().map(Resource::Instance));
+        leftovers.extend(self.security_groups.iter().cloned().map(Resource::SecurityGroup));
+        leftovers.extend(self.key_pairs.iter().cloned().map(Resource::KeyPair));
+        leftovers
+    }
+
+    fn set_ledger(&mut self, ledger: Ledger) {
+        self.ledger = Some(ledger);
+    }
+
+    fn set_tags(&mut self, tags: &[(String, String)]) {
+        self.tags = tags.to_vec();
+    }
+
+    fn set_max_duration(&mut self, max_duration: Duration) {
+        self.max_duration = Some(max_duration);
+    }
+
+    fn set_key_pair(&mut self, key_pair: KeyPair) -> Result<(), Error> {
+        match key_pair {
+            KeyPair::Generated => self.key = Key::Generated,
+            KeyPair::Local(path) => {
+                let public_key = local::public_key(&path)?;
+                self.set_key(&path, &public_key);
+            }
+            KeyPair::Existing { name, private_key } => self.key = Key::Existing { name, private_key },
+        }
+        Ok(())
+    }
+}
+
+/*
+ * User data that makes an instance shut itself down, and thereby terminate, `max_duration` after it booted.
+ * shutdown only takes whole minutes, so the duration is rounded up.
+ */
+fn shutdown_script(max_duration: Duration) -> String {
+    let minutes = max_duration.as_secs().div_ceil(60);
+    base64::encode(format!("#!/bin/sh\nshutdown -h +{}\n", minutes))
+}
+
+/*
+ * Access is the security group and key pair that instances are launched with.
+ */
+struct Access {
+    group_id: String,
+    key_name: String,
+}
+
+/*
+ * Tag keys of the machine set name and of the index within the set, of spot requests and instances.
+ */
+pub const SET_TAG: &str = "burst:set";
+pub const INDEX_TAG: &str = "burst:machine-index";
+
+/*
+ * The machine set a spot request AWS described was issued for, by its id in `id_to_name`.
+ */
+fn set_of(id_to_name: &HashMap<String, String>, sir: &rusoto_ec2::SpotInstanceRequest) -> Result<String, Error> {
+    let id = spot::request_id(sir)?;
+    id_to_name.get(id)
+        .cloned()
+        .ok_or_else(|| failure::format_err!("aws described spot request {}, which was never issued", id))
+}
+
+/*
+ * The availability zones and instance types the spot request and the requests it replaced were tried in and with,
+ * its own included, by the ids of the requests they replaced in `tried`.
+ */
+fn tried_so_far(
+    status: &SpotRequestStatus,
+    setup: &MachineSetup,
+    tried: &HashMap<String, (Vec<String>, Vec<String>)>,
+) -> (Vec<String>, Vec<String>) {
+    let (mut zones_tried, mut types_tried) = tried.get(&status.id).cloned().unwrap_or_default();
+    zones_tried.extend(status.zone.clone().filter(|zone| !zones_tried.contains(zone)));
+    let instance_type = status.instance_type.clone().unwrap_or_else(|| setup.instance_type().to_string());
+    if !types_tried.contains(&instance_type) {
+        types_tried.push(instance_type);
+    }
+    (zones_tried, types_tried)
+}
+
+fn random_name(prefix: &str) -> String {
+    let mut name = String::from(prefix);
+    name.extend(rand::thread_rng().sample_iter(&Alphanumeric).take(10).map(char::from));
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::FakeEc2;
+    use crate::poll::WaitTimeout;
+
+    fn sets(sets: &[(&str, &str, u32)]) -> HashMap<String, (MachineSetup, u32)> {
+        sets.iter()
+            .map(|&(name, instance_type, number)| {
+                (name.to_string(), (MachineSetup::new(instance_type, "ami-e18aa89b", |_| Ok(())), number))
+            })
+            .collect()
+    }
+
+    fn backend(fake: &FakeEc2) -> Ec2Backend<FakeEc2> {
+        let mut backend = Ec2Backend::with_client("us-east-1", fake.clone()).unwrap();
+        backend.set_backoff(crate::poll::TEST_BACKOFF);
+        backend
+    }
+
+    fn log() -> slog::Logger {
+        slog::Logger::root(slog::Discard, o!())
+    }
+
+    #[tokio::test]
+    async fn provisions_every_set_and_terminates_it() {
+        let fake = FakeEc2::new("us-east-1");
+        fake.set_fulfillment_polls(3);
+        fake.set_address_polls(2);
+        let mut backend = backend(&fake);
+
+        let machines = backend
+            .provision(&log(), &sets(&[("server", "t3.small", 1), ("client", "t3.micro", 3)]))
+            .await
+            .unwrap();
+        assert_eq!(machines["server"].len(), 1);
+        assert_eq!(machines["client"].len(), 3);
+        assert!(machines["client"].iter().all(|m| m.instance_type == "t3.micro"));
+        assert_eq!(fake.instances_in_state("running").len(), 4);
+        assert_eq!(fake.spot_requests_in_state("cancelled").len(), 4);
+        assert_eq!(fake.security_groups().len(), 1);
+        assert_eq!(fake.key_pairs().len(), 1);
+
+        assert!(backend.teardown(&log()).await.is_empty());
+        assert_eq!(fake.instances_in_state("terminated").len(), 4);
+        assert!(fake.security_groups().is_empty());
+        assert!(fake.key_pairs().is_empty());
+    }
+
+    #[tokio::test]
+    async fn capacity_failure_still_terminates_fulfilled_instances() {
+        let fake = FakeEc2::new("us-east-1");
+        fake.set_capacity("c5.large", 1);
+        let mut backend = backend(&fake);
+
+        let err = backend
+            .provision(&log(), &sets(&[("client", "c5.large", 2)]))
+            .await
+            .err()
+            .expect("only one of two instances fits");
+        let failed = err.downcast_ref::<SpotRequestFailed>().expect("spot request failure");
+        assert_eq!(failed.status.state, SpotRequestState::Open);
+        assert_eq!(failed.status.code, "capacity-not-available");
+        assert!(failed.status.message.contains("no Spot capacity"));
+        assert!(failed.zones_tried.is_empty());
+        assert!(fake.spot_requests_in_state("open").is_empty());
+
+        assert!(backend.teardown(&log()).await.is_empty());
+        assert_eq!(fake.instances_in_state("terminated").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_spot_request_is_reported() {
+        let fake = FakeEc2::new("us-east-1");
+        let mut backend = backend(&fake);
+        let mut sets = sets(&[]);
+        sets.insert("server".to_string(), (MachineSetup::new("t3.small", "not-an-ami", |_| Ok(())), 1));
+
+        let err = backend.provision(&log(), &sets).await.err().expect("bad image id");
+        assert!(err.to_string().contains("ended up failed (bad-parameters"), "{}", err);
+        assert!(backend.teardown(&log()).await.is_empty());
+        assert!(fake.instances_in_state("pending").is_empty());
+    }
+
+    #[tokio::test]
+    async fn teardown_retries_dropped_connections() {
+        let fake = FakeEc2::new("us-east-1");
+        let mut backend = backend(&fake);
+        backend.provision(&log(), &sets(&[("server", "t3.small", 2)])).await.unwrap();
+
+        fake.fail_next("terminate_instances", "Pooled stream disconnected");
+        assert!(backend.teardown(&log()).await.is_empty());
+        assert_eq!(fake.instances_in_state("terminated").len(), 2);
+    }
+
+    #[tokio::test]
+    async fn teardown_retries_security_group_still_in_use() {
+        let fake = FakeEc2::new("us-east-1");
+        let mut backend = backend(&fake);
+        backend.provision(&log(), &sets(&[("server", "t3.small", 1)])).await.unwrap();
+
+        fake.fail_next("delete_security_group", "DependencyViolation: resource sg-1 has a dependent object");
+        fake.fail_next("delete_security_group", "DependencyViolation: resource sg-1 has a dependent object");
+        assert!(backend.teardown(&log()).await.is_empty());
+        assert!(fake.security_groups().is_empty());
+    }
+
+    #[tokio::test]
+    async fn teardown_reports_leftovers() {
+        let fake = FakeEc2::new("us-east-1");
+        let mut backend = backend(&fake);
+        backend.provision(&log(), &sets(&[("server", "t3.small", 1)])).await.unwrap();
+
+        fake.fail_next("terminate_instances", "UnauthorizedOperation: not allowed");
+        let leftovers = backend.teardown(&log()).await;
+        assert_eq!(leftovers.len(), 2, "{:?}", leftovers);
+        assert!(matches!(leftovers[0], Resource::Instance(_)));
+        assert!(matches!(leftovers[1], Resource::SecurityGroup(_)));
+        assert!(fake.key_pairs().is_empty());
+    }
+
+    #[tokio::test]
+    async fn tags_every_resource_and_finds_them_again() {
+        let fake = FakeEc2::new("us-east-1");
+        let mut backend = backend(&fake);
+        backend.set_tags(&[("burst:run-id".to_string(), "run-1".to_string())]);
+        let machines = backend.provision(&log(), &sets(&[("client", "t3.micro", 3)])).await.unwrap();
+
+        let mut indices: Vec<String> = fake.instances_in_state("running")
+            .iter()
+            .map(|id| fake.tags(id))
+            .inspect(|tags| assert_eq!(tags[SET_TAG], "client"))
+            .map(|tags| tags[INDEX_TAG].clone())
+            .collect();
+        indices.sort();
+        assert_eq!(indices, ["0", "1", "2"]);
+        for (index, machine) in machines["client"].iter().enumerate() {
+            let instance = fake.instances_in_state("running")
+                .into_iter()
+                .find(|id| fake.tags(id)[INDEX_TAG] == index.to_string())
+                .unwrap();
+            assert_eq!(fake.tags(&instance)["burst:run-id"], "run-1");
+            let n = u64::from_str_radix(&instance[2..], 16).unwrap();
+            assert_eq!(machine.private_ip, format!("172.31.{}.{}", n / 256, n % 256));
+        }
+        assert!(fake.spot_requests_in_state("cancelled").iter().all(|id| fake.tags(id)[SET_TAG] == "client"));
+
+        let tagged = backend.find_tagged("burst:run-id", "run-1").await.unwrap();
+        assert_eq!(tagged.len(), 5, "{:?}", tagged);
+        assert!(backend.find_tagged("burst:run-id", "run-2").await.unwrap().is_empty());
+
+        let mut reaper = Ec2Backend::with_client("us-east-1", fake.clone()).unwrap();
+        reaper.set_backoff(crate::poll::TEST_BACKOFF);
+        assert!(reaper.reap(&log(), tagged).await.is_empty());
+        assert_eq!(fake.instances_in_state("terminated").len(), 3);
+        assert!(fake.security_groups().is_empty());
+        assert!(fake.key_pairs().is_empty());
+    }
+
+    #[tokio::test]
+    async fn records_instances_while_still_waiting_on_spot_requests() {
+        let fake = FakeEc2::new("us-east-1");
+        fake.set_spot_price("t3.small", 0.02);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ledger");
+        let mut backend = backend(&fake);
+        backend.set_ledger(Ledger::open(&path).unwrap());
+        let mut sets = sets(&[("client", "c5.large", 1), ("server", "t3.small", 1)]);
+        sets.get_mut("server").unwrap().0.set_market(Market::Spot { max_price: Some(0.01) });
+        sets.get_mut("server").unwrap().0.set_fulfillment(0, Duration::from_secs(3600));
+
+        // the process dies while the server's request is still waiting for the price to drop
+        let provision = tokio::time::timeout(Duration::from_millis(200), backend.provision(&log(), &sets)).await;
+        assert!(provision.is_err());
+        drop(backend);
+        let launched = fake.instances_in_state("pending");
+        assert_eq!(launched.len(), 1);
+        let outstanding: Vec<Resource> = Ledger::open(&path).unwrap().outstanding().unwrap()
+            .into_iter()
+            .map(|(_, resource)| resource)
+            .collect();
+        assert!(outstanding.contains(&Resource::Instance(launched[0].clone())), "{:?}", outstanding);
+
+        let mut reaper = self::backend(&fake);
+        assert!(reaper.reap(&log(), outstanding).await.is_empty());
+        assert_eq!(fake.instances_in_state("terminated"), launched);
+        assert!(fake.spot_requests_in_state("open").is_empty());
+    }
+
+    #[tokio::test]
+    async fn reaps_the_instances_of_spot_requests() {
+        let fake = FakeEc2::new("us-east-1");
+        let res = fake.request_spot_instances(rusoto_ec2::RequestSpotInstancesRequest {
+            instance_count: Some(1),
+            launch_specification: Some(rusoto_ec2::RequestSpotLaunchSpecification {
+                image_id: Some("ami-e18aa89b".to_string()),
+                instance_type: Some("t3.small".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }).await.unwrap();
+        let id = res.spot_instance_requests.unwrap()[0].spot_instance_request_id.clone().unwrap();
+        let req = rusoto_ec2::DescribeSpotInstanceRequestsRequest {
+            spot_instance_request_ids: Some(vec![id.clone()]),
+            ..Default::default()
+        };
+        while fake.instances_in_state("pending").is_empty() {
+            let _ = fake.describe_spot_instance_requests(req.clone()).await;
+        }
+
+        // a ledger that only got to record the spot request
+        let mut reaper = backend(&fake);
+        assert!(reaper.reap(&log(), vec![Resource::SpotRequest(id)]).await.is_empty());
+        assert_eq!(fake.instances_in_state("terminated").len(), 1);
+        assert_eq!(fake.spot_requests_in_state("closed").len() + fake.spot_requests_in_state("cancelled").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn instances_shut_themselves_down_after_max_duration() {
+        let fake = FakeEc2::new("us-east-1");
+        let mut backend = backend(&fake);
+        backend.set_max_duration(Duration::from_secs(90 * 60 + 1));
+        backend.provision(&log(), &sets(&[("server", "t3.small", 2)])).await.unwrap();
+
+        for instance in fake.instances_in_state("running") {
+            assert_eq!(fake.shutdown_behavior(&instance).as_deref(), Some("terminate"));
+            let script = base64::decode(fake.user_data(&instance).unwrap()).unwrap();
+            assert_eq!(String::from_utf8(script).unwrap(), "#!/bin/sh\nshutdown -h +91\n");
+        }
+        assert!(backend.teardown(&log()).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn launches_on_demand_sets_without_spot_requests() {
+        let fake = FakeEc2::new("us-east-1");
+        let mut backend = backend(&fake);
+        let mut sets = sets(&[("server", "t3.small", 2)]);
+        sets.get_mut("server").unwrap().0.set_market(Market::OnDemand);
+        let machines = backend.provision(&log(), &sets).await.unwrap();
+
+        assert_eq!(machines["server"].len(), 2);
+        assert!(fake.spot_requests_in_state("cancelled").is_empty());
+        for instance in fake.instances_in_state("running") {
+            assert!(!fake.is_spot(&instance));
+            assert_eq!(fake.shutdown_behavior(&instance).as_deref(), Some("terminate"));
+            assert_eq!(fake.tags(&instance)[SET_TAG], "server");
+        }
+        assert!(backend.teardown(&log()).await.is_empty());
+        assert_eq!(fake.instances_in_state("terminated").len(), 2);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_on_demand_for_what_spot_cannot_provide() {
+        let fake = FakeEc2::new("us-east-1");
+        fake.set_capacity("c5.large", 1);
+        fake.set_spot_price("t3.small", 0.02);
+        let mut backend = backend(&fake);
+        let mut sets = sets(&[("client", "c5.large", 3), ("server", "t3.small", 1)]);
+        sets.get_mut("client").unwrap().0.set_market(Market::SpotThenOnDemand {
+            max_price: None,
+            timeout: Duration::from_secs(3600),
+        });
+        sets.get_mut("server").unwrap().0.set_market(Market::SpotThenOnDemand {
+            max_price: Some(0.01),
+            timeout: Duration::from_millis(10),
+        });
+        let machines = backend.provision(&log(), &sets).await.unwrap();
+
+        assert_eq!(machines["client"].len(), 3);
+        assert_eq!(machines["server"].len(), 1);
+        let running = fake.instances_in_state("running");
+        assert_eq!(running.iter().filter(|i| fake.is_spot(i)).count(), 1);
+        let mut indices: Vec<String> = running.iter()
+            .map(|i| fake.tags(i))
+            .filter(|tags| tags[SET_TAG] == "client")
+            .map(|tags| tags[INDEX_TAG].clone())
+            .collect();
+        indices.sort();
+        assert_eq!(indices, ["0", "1", "2"]);
+        assert_eq!(fake.spot_requests_in_state("cancelled").len(), 2);
+        assert_eq!(fake.spot_requests_in_state("closed").len(), 2);
+
+        assert!(backend.teardown(&log()).await.is_empty());
+        assert_eq!(fake.instances_in_state("terminated").len(), 4);
+    }
+
+    #[tokio::test]
+    async fn retries_spot_requests_in_other_zones() {
+        let fake = FakeEc2::new("us-east-1");
+        fake.set_zone_capacity("us-east-1a", "c5.large", 1);
+        fake.set_zone_capacity("us-east-1b", "c5.large", 0);
+        let mut backend = backend(&fake);
+        backend.set_retry_other_zones(true);
+        let machines = backend.provision(&log(), &sets(&[("client", "c5.large", 3)])).await.unwrap();
+        assert_eq!(machines["client"].len(), 3);
+        assert_eq!(fake.instances_in_state("running").len(), 3);
+        assert!(backend.teardown(&log()).await.is_empty());
+
+        fake.set_zone_capacity("us-east-1c", "c5.large", 0);
+        let mut backend = self::backend(&fake);
+        backend.set_retry_other_zones(true);
+        let err = backend.provision(&log(), &sets(&[("client", "c5.large", 1)])).await.err().expect("no zone has room");
+        let failed = err.downcast_ref::<SpotRequestFailed>().expect("spot request failure");
+        assert_eq!(failed.zones_tried, ["us-east-1a", "us-east-1b", "us-east-1c"]);
+        assert!(backend.teardown(&log()).await.is_empty());
+        assert!(fake.spot_requests_in_state("open").is_empty());
+    }
+
+    #[tokio::test]
+    async fn waits_out_throttled_requests() {
+        let fake = FakeEc2::new("us-east-1");
+        let mut backend = backend(&fake);
+        for op in &["describe_spot_instance_requests", "create_tags", "describe_instances", "terminate_instances"] {
+            fake.fail_next(op, "RequestLimitExceeded: Request limit exceeded.");
+        }
+
+        let machines = backend.provision(&log(), &sets(&[("server", "t3.small", 2)])).await.unwrap();
+        assert_eq!(machines["server"].len(), 2);
+        assert!(backend.teardown(&log()).await.is_empty());
+        assert_eq!(fake.instances_in_state("terminated").len(), 2);
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_instances_that_never_get_an_address() {
+        let fake = FakeEc2::new("us-east-1");
+        fake.set_address_polls(u32::MAX);
+        let mut backend = backend(&fake);
+        backend.set_backoff(Backoff {
+            deadline: Duration::from_millis(50),
+            ..crate::poll::TEST_BACKOFF
+        });
+
+        let err = match backend.provision(&log(), &sets(&[("server", "t3.small", 1)])).await {
+            Ok(_) => panic!("instance without an address was provisioned"),
+            Err(e) => e,
+        };
+        let timeout = err.downcast_ref::<WaitTimeout>().expect("provisioning should time out");
+        assert_eq!(timeout.what, "instances to get their addresses");
+        assert!(backend.teardown(&log()).await.is_empty());
+        assert_eq!(fake.instances_in_state("terminated").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn settles_for_the_minimum_of_a_set() {
+        let fake = FakeEc2::new("us-east-1");
+        fake.set_capacity("c5.large", 4);
+        fake.set_on_demand_capacity("t3.small", 2);
+        let mut backend = backend(&fake);
+        let mut sets = sets(&[("client", "c5.large", 5), ("server", "t3.small", 3)]);
+        sets.get_mut("client").unwrap().0.set_fulfillment(3, Duration::from_millis(50));
+        sets.get_mut("server").unwrap().0.set_market(Market::OnDemand);
+        sets.get_mut("server").unwrap().0.set_fulfillment(2, Duration::from_millis(50));
+        let machines = backend.provision(&log(), &sets).await.unwrap();
+
+        assert_eq!(machines["client"].len(), 4);
+        assert_eq!(machines["server"].len(), 2);
+        assert!(fake.spot_requests_in_state("open").is_empty());
+        assert!(backend.teardown(&log()).await.is_empty());
+        assert_eq!(fake.instances_in_state("terminated").len(), 6);
+
+        // a minimum above the number asked for is capped at it
+        let mut backend = self::backend(&fake);
+        let mut sets = self::sets(&[("server", "t3.micro", 2)]);
+        sets.get_mut("server").unwrap().0.set_market(Market::OnDemand);
+        sets.get_mut("server").unwrap().0.set_fulfillment(3, Duration::from_millis(50));
+        assert_eq!(backend.provision(&log(), &sets).await.unwrap()["server"].len(), 2);
+        assert!(backend.teardown(&log()).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn waits_for_set_deadlines_beyond_the_backoff_deadline() {
+        let fake = FakeEc2::new("us-east-1");
+        fake.set_spot_price("t3.small", 0.02);
+        let mut backend = backend(&fake);
+        backend.set_backoff(Backoff {
+            deadline: Duration::from_millis(100),
+            ..crate::poll::TEST_BACKOFF
+        });
+        let mut sets = sets(&[("client", "t3.small", 1), ("server", "t3.small", 1), ("cache", "t3.micro", 1)]);
+        sets.get_mut("client").unwrap().0.set_market(Market::Spot { max_price: Some(0.01) });
+        sets.get_mut("client").unwrap().0.set_fulfillment(0, Duration::from_millis(500));
+        sets.get_mut("server").unwrap().0.set_market(Market::SpotThenOnDemand {
+            max_price: Some(0.01),
+            timeout: Duration::from_millis(300),
+        });
+        let machines = backend.provision(&log(), &sets).await.unwrap();
+
+        assert!(machines.get("client").is_none_or(Vec::is_empty));
+        assert_eq!(machines["server"].len(), 1);
+        assert_eq!(machines["cache"].len(), 1);
+        assert!(backend.teardown(&log()).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn fails_when_a_set_falls_short_of_its_minimum() {
+        let fake = FakeEc2::new("us-east-1");
+        fake.set_capacity("c5.large", 2);
+        let mut backend = backend(&fake);
+        let mut sets = sets(&[("client", "c5.large", 5)]);
+        sets.get_mut("client").unwrap().0.set_fulfillment(3, Duration::from_millis(50));
+        let err = match backend.provision(&log(), &sets).await {
+            Ok(_) => panic!("provisioning should fail below the minimum"),
+            Err(e) => e,
+        };
+
+        let short = err.downcast_ref::<InsufficientMachines>().expect("insufficient machines");
+        assert_eq!((short.set.as_str(), short.wanted, short.min, short.obtained), ("client", 5, 3, 2));
+        assert!(backend.teardown(&log()).await.is_empty());
+        assert_eq!(fake.instances_in_state("terminated").len(), 2);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_other_instance_types() {
+        let fake = FakeEc2::new("us-east-1");
+        fake.set_capacity("c5.xlarge", 1);
+        fake.set_on_demand_capacity("c5.xlarge", 1);
+        let mut backend = backend(&fake);
+        let mut sets = sets(&[("client", "c5.xlarge", 3), ("server", "c5.xlarge", 2)]);
+        sets.get_mut("client").unwrap().0.set_instance_types(&["c5.xlarge", "c5a.xlarge"]);
+        sets.get_mut("server").unwrap().0.set_instance_types(&["c5.xlarge", "c6i.xlarge"]);
+        sets.get_mut("server").unwrap().0.set_market(Market::OnDemand);
+        let machines = backend.provision(&log(), &sets).await.unwrap();
+
+        let types = |set: &str| {
+            let mut types: Vec<&str> = machines[set].iter().map(|m| m.instance_type.as_str()).collect();
+            types.sort_unstable();
+            types
+        };
+        assert_eq!(types("client"), ["c5.xlarge", "c5a.xlarge", "c5a.xlarge"]);
+        assert_eq!(types("server"), ["c5.xlarge", "c6i.xlarge"]);
+        assert!(backend.teardown(&log()).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_other_instance_types_before_the_deadline() {
+        let fake = FakeEc2::new("us-east-1");
+        fake.set_capacity("c5.xlarge", 1);
+        fake.set_zone_capacity("us-east-1b", "c5.large", 0);
+        let mut backend = backend(&fake);
+        let mut sets = sets(&[("client", "c5.xlarge", 2), ("server", "c5.large", 1)]);
+        sets.get_mut("client").unwrap().0.set_instance_types(&["c5.xlarge", "c5a.xlarge"]);
+        sets.get_mut("client").unwrap().0.set_fulfillment(2, Duration::from_secs(3600));
+        sets.get_mut("server").unwrap().0.set_availability_zones(&["us-east-1b", "us-east-1c"]);
+        sets.get_mut("server").unwrap().0.set_market(Market::SpotThenOnDemand {
+            max_price: None,
+            timeout: Duration::from_secs(3600),
+        });
+        let machines = backend.provision(&log(), &sets).await.unwrap();
+
+        let mut types: Vec<&str> = machines["client"].iter().map(|m| m.instance_type.as_str()).collect();
+        types.sort_unstable();
+        assert_eq!(types, ["c5.xlarge", "c5a.xlarge"]);
+        assert_eq!(machines["server"][0].availability_zone, "us-east-1c");
+        assert!(fake.instances_in_state("running").iter().all(|i| fake.is_spot(i)));
+        assert!(backend.teardown(&log()).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reports_every_instance_type_tried() {
+        let fake = FakeEc2::new("us-east-1");
+        fake.set_capacity("c5.xlarge", 0);
+        fake.set_capacity("c5a.xlarge", 0);
+        let mut backend = backend(&fake);
+        let mut sets = sets(&[("client", "c5.xlarge", 1)]);
+        sets.get_mut("client").unwrap().0.set_instance_types(&["c5.xlarge", "c5a.xlarge"]);
+        let err = match backend.provision(&log(), &sets).await {
+            Ok(_) => panic!("provisioning without capacity should fail"),
+            Err(e) => e,
+        };
+
+        let failed = err.downcast_ref::<SpotRequestFailed>().expect("spot request failure");
+        assert_eq!(failed.instance_types_tried, ["c5.xlarge", "c5a.xlarge"]);
+        assert!(failed.to_string().ends_with("with instance types c5.xlarge, c5a.xlarge"), "{}", failed);
+        assert!(backend.teardown(&log()).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn places_machines_in_preferred_zones() {
+        let fake = FakeEc2::new("us-east-1");
+        fake.set_zone_capacity("us-east-1b", "c5.large", 1);
+        let mut backend = backend(&fake);
+        let mut sets = sets(&[("client", "c5.large", 2), ("server", "t3.small", 1)]);
+        sets.get_mut("client").unwrap().0.set_availability_zones(&["us-east-1b", "us-east-1c"]);
+        sets.get_mut("server").unwrap().0.set_availability_zones(&["us-east-1c"]);
+        sets.get_mut("server").unwrap().0.set_market(Market::OnDemand);
+        let machines = backend.provision(&log(), &sets).await.unwrap();
+
+        let mut zones: Vec<&str> = machines["client"].iter().map(|m| m.availability_zone.as_str()).collect();
+        zones.sort_unstable();
+        assert_eq!(zones, ["us-east-1b", "us-east-1c"]);
+        assert_eq!(machines["server"][0].availability_zone, "us-east-1c");
+        assert!(backend.teardown(&log()).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn imports_a_local_key() {
+        let fake = FakeEc2::new("us-east-1");
+        let dir = tempfile::tempdir().unwrap();
+        let key = dir.path().join("id_ed25519");
+        let mut backend = backend(&fake);
+        backend.set_key_pair(KeyPair::Local(key.clone())).unwrap();
+        assert!(key.exists());
+        assert_eq!(backend.private_key(), Some(key.as_path()));
+
+        backend.provision(&log(), &sets(&[("client", "t3.small", 1)])).await.unwrap();
+        let public_key = std::fs::read_to_string(dir.path().join("id_ed25519.pub")).unwrap();
+        assert_eq!(fake.public_key(&fake.key_pairs()[0]).as_deref(), Some(public_key.trim()));
+        assert!(backend.teardown(&log()).await.is_empty());
+        assert!(fake.key_pairs().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reuses_an_existing_key_pair() {
+        let fake = FakeEc2::new("us-east-1");
+        fake.create_key_pair(rusoto_ec2::CreateKeyPairRequest {
+            key_name: "laptop".to_string(),
+            ..Default::default()
+        }).await.unwrap();
+        let mut backend = backend(&fake);
+        backend.set_key_pair(KeyPair::Existing { name: "laptop".to_string(), private_key: None }).unwrap();
+        assert_eq!(backend.private_key(), None);
+
+        backend.provision(&log(), &sets(&[("client", "t3.small", 2)])).await.unwrap();
+        assert!(backend.teardown(&log()).await.is_empty());
+        assert_eq!(fake.key_pairs(), ["laptop"]);
+
+        let mut backend = self::backend(&fake);
+        backend.set_key_pair(KeyPair::Existing { name: "desktop".to_string(), private_key: None }).unwrap();
+        match backend.provision(&log(), &sets(&[("client", "t3.small", 1)])).await {
+            Ok(_) => panic!("provisioned with a key pair that does not exist"),
+            Err(e) => assert!(e.to_string().contains("desktop")),
+        }
+        assert!(backend.teardown(&log()).await.is_empty());
+    }
+}
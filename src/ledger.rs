@@ -0,0 +1,121 @@
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use failure::{Error, ResultExt};
+
+use crate::backend::Resource;
+
+/*
+ * Ledger is an append-only file recording every resource a backend creates, the moment it is created,
+ * and every resource it releases again. Each entry is flushed to disk before the backend carries on,
+ * so if the process is killed mid-run the ledger still knows what was left behind, and burst::reap can
+ * destroy it later.
+ * Lines look like "+ us-east-1 instance i-0123" for created and "- us-east-1 instance i-0123" for released
+ * resources, with the region the resource lives in, since ids mean nothing outside of their region.
+ * Several runs may share one ledger, whichever regions they provision in.
+ */
+pub struct Ledger {
+    path: PathBuf,
+    file: File,
+}
+
+impl Ledger {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context(format!("failed to open ledger {}", path.display()))?;
+        Ok(Ledger {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn created(&mut self, region: &str, resource: &Resource) -> Result<(), Error> {
+        self.append('+', region, resource)
+    }
+
+    pub fn released(&mut self, region: &str, resource: &Resource) -> Result<(), Error> {
+        self.append('-', region, resource)
+    }
+
+    fn append(&mut self, op: char, region: &str, resource: &Resource) -> Result<(), Error> {
+        writeln!(self.file, "{} {} {}", op, region, resource)
+            .and_then(|_| self.file.sync_data())
+            .context(format!("failed to write to ledger {}", self.path.display()))?;
+        Ok(())
+    }
+
+    /*
+     * Resources that were created but never released, with their region, in the order they were created.
+     */
+    pub fn outstanding(&self) -> Result<Vec<(String, Resource)>, Error> {
+        let file = File::open(&self.path).context(format!("failed to open ledger {}", self.path.display()))?;
+        let mut created = Vec::new();
+        let mut released = HashSet::new();
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.context("failed to read ledger")?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let malformed = || failure::format_err!("{}:{}: malformed ledger entry", self.path.display(), i + 1);
+            let entry = |rest: &str| -> Result<(String, Resource), Error> {
+                let (region, resource) = rest.trim().split_once(' ').ok_or_else(malformed)?;
+                Ok((region.to_string(), resource.parse::<Resource>().map_err(|_| malformed())?))
+            };
+            if let Some(rest) = line.strip_prefix('+') {
+                created.push(entry(rest)?);
+            } else if let Some(rest) = line.strip_prefix('-') {
+                released.insert(entry(rest)?);
+            } else {
+                return Err(malformed());
+            }
+        }
+        created.retain(|r| !released.contains(r));
+        created.dedup();
+        Ok(created)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outstanding_skips_released_resources() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ledger");
+
+        let mut ledger = Ledger::open(&path).unwrap();
+        ledger.created("us-east-1", &Resource::SecurityGroup("sg-1".to_string())).unwrap();
+        ledger.created("us-east-1", &Resource::Instance("i-1".to_string())).unwrap();
+        ledger.created("us-east-1", &Resource::SpotRequest("sir-1".to_string())).unwrap();
+        ledger.released("us-east-1", &Resource::Instance("i-1".to_string())).unwrap();
+        drop(ledger);
+
+        let mut ledger = Ledger::open(&path).unwrap();
+        ledger.created("eu-west-1", &Resource::KeyPair("burst_key_a".to_string())).unwrap();
+        // the same id in another region is another resource
+        ledger.created("eu-west-1", &Resource::Instance("i-1".to_string())).unwrap();
+        assert_eq!(ledger.outstanding().unwrap(), vec![
+            ("us-east-1".to_string(), Resource::SecurityGroup("sg-1".to_string())),
+            ("us-east-1".to_string(), Resource::SpotRequest("sir-1".to_string())),
+            ("eu-west-1".to_string(), Resource::KeyPair("burst_key_a".to_string())),
+            ("eu-west-1".to_string(), Resource::Instance("i-1".to_string())),
+        ]);
+    }
+
+    #[test]
+    fn rejects_entries_without_a_region() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ledger");
+        std::fs::write(&path, "+ instance i-1\n").unwrap();
+        assert!(Ledger::open(&path).unwrap().outstanding().is_err());
+    }
+}
@@ -9,52 +9,140 @@ extern crate tokio;
 extern crate tempfile;
 extern crate rayon;
 extern crate failure;
-#[macro_use(defer)] extern crate scopeguard;
 
+use std::any::Any;
 use std::collections::HashMap;
-use std::mem;
 use std::net::IpAddr;
 use std::net::SocketAddr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
 use std::time;
-use rand::distributions::Alphanumeric;
-use rusoto_ec2::Ec2;
 use failure::Error;
+use futures::FutureExt;
 use failure::ResultExt;
-use tokio::runtime::Handle;
-use std::io::{Write};
 use rayon::prelude::*;
-use slog::{Drain, o, info};
+use slog::{Drain, o};
 pub struct SshConnection;
 
 mod ssh;
+mod sftp;
+mod deploy;
+mod spawn;
+mod probe;
+mod backend;
+mod credentials;
+mod ec2;
+#[cfg(any(test, feature = "fake"))]
+mod fake;
+mod local;
+mod ledger;
+mod spot;
+mod poll;
+mod regions;
+
+pub use backend::{Backend, InsufficientMachines, KeyPair, Resource, TeardownIncomplete};
+pub use credentials::{AssumeRole, Credentials};
+pub use deploy::Artifact;
+pub use ec2::{Ec2Api, Ec2Backend, INDEX_TAG, SET_TAG};
+#[cfg(any(test, feature = "fake"))]
+pub use fake::FakeEc2;
+pub use ledger::Ledger;
+pub use poll::{Backoff, WaitTimeout};
+pub use probe::Probe;
+pub use spawn::{Logs, Process};
+pub use spot::{SpotRequestFailed, SpotRequestState, SpotRequestStatus};
+pub use local::LocalBackend;
+pub use regions::MultiRegionBackend;
+pub use sftp::Progress;
+pub use ssh::{CommandFailed, CommandOutput, CommandTimeout, Session, Stream};
 
 /*
  * Machine struct is used to store information about the spot instances which are running in AWS.
+ * ssh: the session to the machine, connected by run before the artifacts and setup routine of its set
  * private_ip: priva te ip address of the ec2 machine
  * public_dns: dns of the ec2 machine
+ * ssh_port: port the ssh server of the machine listens on at its public ip
+ * availability_zone: the availability zone the machine was placed in
+ * ssh_user: the user ssh sessions to the machine log in as, the one that was accepted once the machine is set up
  */
 pub struct Machine {
     pub ssh: Option<ssh::Session>,
+    pub ssh_user: String,
     pub instance_type: String,
+    pub availability_zone: String,
     pub private_ip: String,
     pub public_dns: String,
-    pub public_ip: String
+    pub public_ip: String,
+    pub ssh_port: u16,
 }
  
 /*
  * MachineSetup struct is used to stores description of the spot instances which will be launched in AWS.
  * it has following props: 
- * instance_type: possible type of ec2 machine available in aws
+ * instance_types: the types of ec2 machine acceptable for the set, in order of preference. Spot requests are
+ *   issued for one type at a time, and a request that ran out of capacity is issued again with the next type
+ *   (after the set's other availability zones), so every fallback costs another wait on spot fulfillment.
+ *   This keeps one request per machine, which is what SpotRequestFailed, the ledger and Fulfillment deadlines
+ *   work with, and the preference order strict; an EC2 Fleet over several launch specifications would be
+ *   fulfilled in one wait, but picks among the types by price or capacity rather than by preference.
  * ami: possible machine images in aws
  * setup: A Box containing a trait object (Box<dyn Fn(&mut SshConnection) -> io::Result<()>>) that represents a function to set up the instance. This function takes a mutable reference to an SshConnection and returns an io::Result<()>.
+ * market: how the instances are paid for, spot without a price cap unless changed with set_market
+ * fulfillment: how few machines of the set are enough, all of them unless changed with set_fulfillment
+ * availability_zones: the availability zones to place the machines in, in order of preference, any zone if empty
+ * region: the region to place the machines in, the one set on the BurstBuilder if None
+ * ssh_user: the user to log in as; if None the backend's user and then the users of common AMIs are tried
+ * artifacts: local files copied to every machine before the setup routine runs
+ * fan_out: how many machines the set must have for artifacts to be copied between them rather than from here
  */
 pub struct MachineSetup {
-    instance_type: String,
+    instance_types: Vec<String>,
+    availability_zones: Vec<String>,
+    region: Option<String>,
+    ssh_user: Option<String>,
+    artifacts: Vec<Artifact>,
+    fan_out: Option<u32>,
     ami: String,
-    setup: Box<dyn Fn(&mut ssh::Session) -> Result<(), Error> + Sync> 
+    setup: SetupFn,
+    market: Market,
+    fulfillment: Option<Fulfillment>,
 }
 
+/*
+ * Market is how the instances of a machine set are bought.
+ * Spot: spot instances, paying at most max_price dollars per instance hour if set (the on-demand price otherwise).
+ * OnDemand: on-demand instances, which are pricier but not subject to spot capacity.
+ * SpotThenOnDemand: spot instances, but whatever spot could not provide within `timeout` is launched on-demand.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Market {
+    Spot { max_price: Option<f64> },
+    OnDemand,
+    SpotThenOnDemand { max_price: Option<f64>, timeout: time::Duration },
+}
+
+impl Default for Market {
+    fn default() -> Self {
+        Market::Spot { max_price: None }
+    }
+}
+
+/*
+ * Fulfillment lets a machine set carry on with fewer machines than were asked for in add_set:
+ * whatever was not obtained within `timeout` is given up on, and provisioning only fails with
+ * InsufficientMachines if that leaves fewer than `min` machines.
+ * For SpotThenOnDemand sets the market's own timeout applies, and only what on-demand cannot provide
+ * either is given up on.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fulfillment {
+    pub min: u32,
+    pub timeout: time::Duration,
+}
+
+type SetupFn = Box<dyn Fn(&mut ssh::Session) -> Result<(), Error> + Send + Sync>;
+
 
  /* 
  * Follwing is the implementation of the new method for MachineSetup struct. 
@@ -62,16 +150,146 @@ pub struct MachineSetup {
  * The setup argument is a box containing a trait object which is a function to setup the instance.
  * THe trait bound for setup that is F, implies that the setup parameter must be a function or closure with a 'static lifetime
  * which means that the function/closure stored in the Box wil have lifetime of the program. 
+ * It also has to be Send + Sync, since setup routines run on the threads of BurstBuilder::run.
  */
 impl MachineSetup {
     pub fn new<F>(instance_type: &str, ami: &str, setup: F) -> Self
-    where F: Fn(&mut ssh::Session) -> Result<(), Error> + 'static + Sync,
+    where F: Fn(&mut ssh::Session) -> Result<(), Error> + 'static + Send + Sync,
     {
         MachineSetup {
-            instance_type: instance_type.to_string(),
+            instance_types: vec![instance_type.to_string()],
+            availability_zones: Vec::new(),
+            region: None,
+            ssh_user: None,
+            artifacts: Vec::new(),
+            fan_out: None,
             ami: ami.to_string(),
-            setup: Box::new(setup)
+            setup: Box::new(setup),
+            market: Market::default(),
+            fulfillment: None,
+        }
+    }
+
+    pub fn set_market(&mut self, market: Market) {
+        self.market = market;
+    }
+
+    pub fn market(&self) -> &Market {
+        &self.market
+    }
+
+    /*
+     * Makes provisioning settle for as few as `min` machines of the set once `timeout` has passed.
+     */
+    pub fn set_fulfillment(&mut self, min: u32, timeout: time::Duration) {
+        self.fulfillment = Some(Fulfillment { min, timeout });
+    }
+
+    pub fn fulfillment(&self) -> Option<&Fulfillment> {
+        self.fulfillment.as_ref()
+    }
+
+    /*
+     * Makes the set accept any of `instance_types`, in order of preference, in place of the one it was created with.
+     * Provisioning moves on to the next type when the one before it has no capacity, one spot request at a time
+     * (see MachineSetup); which one a machine ended up with is in Machine::instance_type. An empty list is ignored.
+     */
+    pub fn set_instance_types(&mut self, instance_types: &[&str]) {
+        if !instance_types.is_empty() {
+            self.instance_types = instance_types.iter().map(|t| t.to_string()).collect();
+        }
+    }
+
+    /*
+     * The preferred instance type of the set.
+     */
+    pub fn instance_type(&self) -> &str {
+        &self.instance_types[0]
+    }
+
+    pub fn instance_types(&self) -> &[String] {
+        &self.instance_types
+    }
+
+    /*
+     * Places the machines of the set in the first of `zones` (e.g. "us-east-1b") that has capacity for them,
+     * trying the others in order. Which zone a machine ended up in is in Machine::availability_zone.
+     */
+    pub fn set_availability_zones(&mut self, zones: &[&str]) {
+        self.availability_zones = zones.iter().map(|z| z.to_string()).collect();
+    }
+
+    pub fn availability_zones(&self) -> &[String] {
+        &self.availability_zones
+    }
+
+    /*
+     * Places the machines of the set in `region` (e.g. "eu-west-1") rather than the region of the run,
+     * so that one run can span several regions.
+     */
+    pub fn set_region(&mut self, region: &str) {
+        self.region = Some(region.to_string());
+    }
+
+    pub fn region(&self) -> Option<&str> {
+        self.region.as_deref()
+    }
+
+    /*
+     * Makes ssh sessions to the machines of the set log in as `user` (e.g. "ubuntu" for Ubuntu AMIs),
+     * instead of trying common users until one is accepted.
+     */
+    pub fn set_ssh_user(&mut self, user: &str) {
+        self.ssh_user = Some(user.to_string());
+    }
+
+    pub fn ssh_user(&self) -> Option<&str> {
+        self.ssh_user.as_deref()
+    }
+
+    /*
+     * The users to try logging in as, in order: the set's own user if it has one; otherwise the one that was
+     * already accepted by another machine of the set, the backend's user, then those of common AMIs.
+     */
+    fn ssh_users<'a>(&'a self, default: &'a str, accepted: Option<&'a str>) -> Vec<&'a str> {
+        if let Some(ref user) = self.ssh_user {
+            return vec![user];
+        }
+        let mut users = Vec::new();
+        for user in accepted.into_iter().chain(Some(default)).chain(ssh::FALLBACK_USERS.iter().copied()) {
+            if !users.contains(&user) {
+                users.push(user);
+            }
         }
+        users
+    }
+
+    /*
+     * Copies the local file `local` (e.g. target/release/server) to `remote` on every machine of the set,
+     * before the setup routine runs. Each copy is checked against the local SHA-256 and made executable.
+     * Relative remote paths are relative to the login user's home directory.
+     */
+    pub fn add_artifact(&mut self, local: &Path, remote: &str) {
+        self.artifacts.push(Artifact {
+            local: local.to_path_buf(),
+            remote: remote.to_string(),
+        });
+    }
+
+    pub fn artifacts(&self) -> &[Artifact] {
+        &self.artifacts
+    }
+
+    /*
+     * Makes sets of at least `machines` machines get their artifacts uploaded to one machine only,
+     * from which the others fetch them over the private network. The machines need python3, and curl or wget.
+     */
+    pub fn set_fan_out(&mut self, machines: u32) {
+        self.fan_out = Some(machines);
+    }
+
+    pub fn ami(&self) -> &str {
+        &self.ami
     }
 }
 
@@ -79,12 +297,30 @@ impl MachineSetup {
  * Struct Builder is used for instantiating the burst library with the list of machine sets descibed in the descriptors.
  * Each "machine set" is identified with a unique name, and machine set has n number of machines in it.
  * A machine in the "machine set" is configured with MachineSetup 
- * The max_duration denotes the time till which ec2 spot instances will run before being terminated.
+ * The max_duration denotes the time till which the run, and the ec2 spot instances, may run before being terminated.
+ * The backend is where the machines come from, when none is set run uses an Ec2Backend in `region`,
+ * or a MultiRegionBackend if some machine sets are placed in other regions.
+ * The credentials are what that backend signs its requests with, the standard chain when none are set.
+ * The key_pair is handed to the backend if set, and ssh_agent makes sessions authenticate through the ssh-agent.
+ * The command_timeout is the timeout every session starts out with, see Session::set_timeout.
+ * The log_dir is where the logs of processes spawned on the machines are saved at the end of the run.
+ * The ledger is the file every created resource is recorded in, for burst::reap to find after a crash.
+ * The run_id is a fresh UUID that, together with the user supplied tags, is attached to every resource the run creates.
  */
 pub struct BurstBuilder {
     descriptors: HashMap<String, (MachineSetup, u32)>,
     log: slog::Logger,
-    max_duration: i64,
+    max_duration: time::Duration,
+    backend: Option<Box<dyn Backend>>,
+    region: String,
+    credentials: Option<Credentials>,
+    key_pair: Option<KeyPair>,
+    ssh_agent: bool,
+    command_timeout: Option<time::Duration>,
+    log_dir: Option<PathBuf>,
+    ledger: Option<PathBuf>,
+    run_id: String,
+    tags: Vec<(String, String)>,
 }
 
 /***
@@ -96,7 +332,17 @@ impl Default for BurstBuilder {
         BurstBuilder {
             descriptors: Default::default(),
             log: slog::Logger::root(slog::Discard, o!()),
-            max_duration: 60,
+            max_duration: time::Duration::from_secs(60 * 60),
+            backend: None,
+            region: "us-east-1".to_string(),
+            credentials: None,
+            key_pair: None,
+            ssh_agent: false,
+            command_timeout: None,
+            log_dir: None,
+            ledger: None,
+            run_id: new_run_id(),
+            tags: Vec::new(),
         }
     }
 }
@@ -108,16 +354,105 @@ impl BurstBuilder {
     /*
      * The method "add_set" adds a new "machine set" to the burst builder struct by adding a entry to the 
      * descriptors field.
+     * Unless the MachineSetup has a Fulfillment, the run fails if fewer than `number` machines are obtained.
      */
     pub fn add_set(&mut self, name:&str, number: u32, description: MachineSetup) {
         // TODO : if name is already in use
         self.descriptors.insert(name.to_string(), (description, number));
     } 
     /*
-     * The method "set_max_duration" modifies the max_duration attribute.
+     * The method "set_max_duration" modifies the max_duration attribute, one hour by default.
+     * Once it has passed, run stops waiting for the setup and main routines, tears down and returns an error.
+     * Backends that support it also make the machines terminate themselves after it, in case teardown never happens.
     */ 
     pub fn set_max_duration(&mut self, hours:u8) {
-        self.max_duration = hours as i64 * 60;
+        self.max_duration = time::Duration::from_secs(u64::from(hours) * 60 * 60);
+    }
+
+    /*
+     * The method "set_backend" replaces the provider the machines are provisioned from.
+     */
+    pub fn set_backend<B: Backend + 'static>(&mut self, backend: B) {
+        self.backend = Some(Box::new(backend));
+    }
+
+    /*
+     * The method "set_region" sets the AWS region (e.g. "eu-west-1") the machines are provisioned in,
+     * us-east-1 by default. It has no effect on a backend set with set_backend.
+     */
+    pub fn set_region(&mut self, region: &str) {
+        self.region = region.to_string();
+    }
+
+    /*
+     * The method "set_credentials" makes the backend sign its requests with credentials from `provider`,
+     * e.g. Credentials::profile("experiments") or an AssumeRole, instead of the standard chain.
+     * It has no effect on a backend set with set_backend.
+     */
+    pub fn set_credentials<P>(&mut self, provider: P)
+    where P: rusoto_credential::ProvideAwsCredentials + Send + Sync + 'static
+    {
+        self.credentials = Some(Credentials::new(provider));
+    }
+
+    /*
+     * The method "set_key_pair" picks the key pair the machines let ssh sessions in with, see KeyPair.
+     * By default the backend generates one for the run, e.g. KeyPair::Local(~/.ssh/id_ed25519) lets you
+     * ssh in manually during and after the run instead.
+     */
+    pub fn set_key_pair(&mut self, key_pair: KeyPair) {
+        self.key_pair = Some(key_pair);
+    }
+
+    /*
+     * The method "use_ssh_agent" makes ssh sessions authenticate through the ssh-agent (SSH_AUTH_SOCK)
+     * rather than with the private key file of the backend, e.g. for passphrase protected keys.
+     * The agent can only hold a key that exists outside of the run, so run fails unless set_key_pair
+     * picked a KeyPair other than KeyPair::Generated.
+     */
+    pub fn use_ssh_agent(&mut self) {
+        self.ssh_agent = true;
+    }
+
+    /*
+     * The method "set_command_timeout" makes commands run on the machines, by setup routines and the main
+     * routine alike, fail with CommandTimeout once they have run for longer than `timeout`, rather than
+     * hang the run. Sessions can change or lift it with Session::set_timeout.
+     */
+    pub fn set_command_timeout(&mut self, timeout: time::Duration) {
+        self.command_timeout = Some(timeout);
+    }
+
+    /*
+     * The method "set_log_dir" makes run save what processes spawned with Session::spawn wrote, once they
+     * are stopped at the end of the run, as {dir}/{set}/{ip}-{pid}.stdout and .stderr, instead of logging it.
+     */
+    pub fn set_log_dir(&mut self, dir: &Path) {
+        self.log_dir = Some(dir.to_path_buf());
+    }
+
+    /*
+     * The method "set_ledger" makes run record every resource it creates in the ledger file at `path`.
+     * If the process dies mid-run, burst::reap(path) destroys whatever was left behind.
+     */
+    pub fn set_ledger(&mut self, path: &Path) {
+        self.ledger = Some(path.to_path_buf());
+    }
+
+    /*
+     * The method "add_tag" attaches the tag `key` = `value` (e.g. owner or project) to every resource the run creates,
+     * next to the RUN_ID_TAG. A later tag with the same key replaces an earlier one.
+     */
+    pub fn add_tag(&mut self, key: &str, value: &str) {
+        self.tags.retain(|(k, _)| k != key);
+        self.tags.push((key.to_string(), value.to_string()));
+    }
+
+    /*
+     * The id every resource of this run is tagged with under RUN_ID_TAG, e.g. to reap them with burst::reap_tagged.
+     */
+    pub fn run_id(&self) -> &str {
+        &self.run_id
     }
 
     pub fn set_logger(&mut self, log:slog::Logger) {
@@ -133,379 +468,546 @@ impl BurstBuilder {
     }
 
     /*
-     * The method "add_set" adds a new "machine set" to the burst builder struct by adding a entry to the 
-     * descriptors field.
+     * The method "run" provisions all the machine sets through the backend, runs the setup routine of every
+     * machine, hands the machines over to the main routine `f`, and finally tears the backend down again.
+     * The main routine is handed exactly the machines obtained, which for a set with a Fulfillment may be
+     * fewer than were asked for.
+     * Teardown is awaited before run returns, whether provisioning, setup and the main routine succeeded,
+     * failed or panicked. If it leaves resources behind, run returns a TeardownIncomplete error listing them.
+     * A panic is resumed once teardown is done.
+     * The setup and main routines run on a thread of their own, which is abandoned if max_duration passes
+     * before they finish; the machines they were using are gone by the time run returns.
+     * Processes spawned on the machines are stopped and collected before teardown, however the routines ended,
+     * unless that takes longer than a minute, e.g. because abandoned routines still hold on to the sessions.
+     * Since the routines run on that thread, the main routine has to be Send + 'static, and so does the setup
+     * routine of every MachineSetup (Send + Sync): they cannot borrow from the caller's stack, and share state
+     * with it through e.g. an Arc<Mutex<_>> instead.
     */ 
     #[tokio::main]
-    pub async fn run<F>(self, f: F) -> Result<(), Error>
-    where F: FnOnce(HashMap<String, Vec<Machine>>) -> Result<(), Error> 
+    pub async fn run<F>(mut self, f: F) -> Result<(), Error>
+    where F: FnOnce(HashMap<String, Vec<Machine>>) -> Result<(), Error> + Send + 'static
     {
-        //let provider = rusoto::EnvironmentProvider;
-        use rusoto_core::{Region};
-        use rusoto_credential::{EnvironmentProvider};
-       
-        let log = &self.log;
-        debug!(log, "connecting to ec2");
-        /*
-        * Here we create a Ec2Client object with a credentials provider and region etc  
-        */
-        let credentials_provider = EnvironmentProvider::default();
-        let ec2 = rusoto_ec2::Ec2Client::new_with(
-            rusoto_core::HttpClient::new()
-            .context("falied to create tls session for the ec2 api client")?,
-            credentials_provider,
-            Region::UsEast1);
-
-        info!(log, "spinning up tusnami");
-        /**
-         * Creating a security group
-         */
-        use rand::Rng;
-        let mut group_name = String::from("burst_security_");
-        group_name.extend(rand::thread_rng().sample_iter(&Alphanumeric).take(10).map(char::from));
-        
-        let mut req = rusoto_ec2::CreateSecurityGroupRequest::default();
-        req.group_name = group_name.clone();
-        req.description = "Temporary access groups for burst vms".to_string();
-            
-        trace!(log, "creating a security group name"; "name" => group_name);
-        let res: rusoto_ec2::CreateSecurityGroupResult = ec2.create_security_group(req).await
-                                                            .context("falied to create security groups for new machine")?;
-
-        let group_id = res.group_id.expect("aws created security group with no group id");
-
-        trace!(log, "created security group"; "id" => &group_id);
-
-        // Adding rules to security group for ssh access and intra-machine communication
-        let mut req = rusoto_ec2::AuthorizeSecurityGroupIngressRequest::default();
-        req.group_id = Some(group_id.clone());
-        req.ip_protocol = Some("tcp".to_string());
-        req.from_port = Some(22);
-        req.to_port = Some(22);
-        req.cidr_ip = Some("0.0.0.0/0".to_string());
-        trace!(log, "adding ssh access to security group");
-        let _ = ec2.authorize_security_group_ingress(req).await
-                    .context("falied to fill in security groups for new machine")?;
-
-        // Cross-VM Talk
-        let mut req = rusoto_ec2::AuthorizeSecurityGroupIngressRequest::default();
-        req.group_id = Some(group_id.clone());
-        req.ip_protocol = Some("tcp".to_string());
-        req.from_port = Some(0);
-        req.to_port = Some(65535);
-        req.cidr_ip = Some("172.31.0.0/16".to_string());
-        trace!(log, "adding internal VM access to security group");
-        let _ = ec2.authorize_security_group_ingress(req).await
-                    .context("falied to fill in security groups for new machine")?;
-
-        trace!(log, "creating keypair");
-        // creating a key pair 
-        let mut req = rusoto_ec2::CreateKeyPairRequest::default();
-        let mut key_name = String::from("burst_key_");
-        key_name.extend(rand::thread_rng().sample_iter(&Alphanumeric).take(10).map(char::from));
-        req.key_name = key_name.clone();
-
-        let res = ec2.create_key_pair(req).await
-                                .context("falied to generate new key pair")?;
-        trace!(log, "created keypair"; "fingerprint" => res.key_fingerprint);
-        let private_key = res.key_material.expect("aws did not generate key material for new key");
-
-        // saving private key obtained to a temporary file for futhur usage like ssh
-        let mut private_key_file = tempfile::NamedTempFile::new().context("failed to create temporary file for key-pair")?;
-        private_key_file.write_all(private_key.as_bytes())
-            .context("could not write private key to the file")?;
-
-        if let Some(filename) = Path::new(private_key_file.path()).to_str() {
-            trace!(log, "wrote keypair to file"; "filename" => filename) ;
-        } else {
-
+        let log = self.log.clone();
+        if self.ssh_agent && matches!(self.key_pair, None | Some(KeyPair::Generated)) {
+            return Err(failure::err_msg(
+                "use_ssh_agent needs a key pair set with set_key_pair, the ssh-agent cannot hold a generated one"
+            ));
         }
-       
-        let mut setup_fns = HashMap::new();
-        /*
-        * Here we are calling requesting spot instances for all the machine sets and storing the request ids in spot_req_ids.
-        */
-        let mut id_to_name = HashMap::new();
-        let mut spot_req_ids = Vec::new();
-        debug!(log, "issuing spot requests");
-        for (name, (setup, number)) in self.descriptors {
-            let mut launch = rusoto_ec2::RequestSpotLaunchSpecification::default();
-            launch.image_id = Some(setup.ami);
-            launch.instance_type =Some(setup.instance_type);
-            setup_fns.insert(name.clone(), setup.setup);
-
-            launch.security_group_ids = Some(vec![group_id.clone()]);
-            launch.key_name = Some(key_name.clone());
-            
-
-            let mut req = rusoto_ec2::RequestSpotInstancesRequest::default();          
-            req.instance_count = Some(i64::from(number));
-            // TODO
-            // req.block_duration_minutes = Some(self.max_duration);
-            req.launch_specification = Some(launch);
-            let res = ec2.request_spot_instances(req).await
-                                                     .context(format!("falied to request spot instance for {}", name))?;
-
-            trace!(log, "issuing spot request for {}", name;"#" => number);
-            if let Some(spot_instance_requests) = res.spot_instance_requests {
-                // Handle spot_requests.
-                spot_req_ids.extend(
-                    spot_instance_requests.into_iter()
-                    .filter_map(
-                        |sir| sir.spot_instance_request_id
-                    )
-                    .map(|sir| {
-                        trace!(log, "activated spot request"; "id" => &sir);
-                        id_to_name.insert(sir.clone(), name.clone());
-                        sir
-                    })
-                );
-            } else {
-                // Handle the case when spot_instance_requests is None.
+        let mut backend = match self.backend.take() {
+            Some(backend) => backend,
+            None => {
+                let credentials = match self.credentials.take() {
+                    Some(credentials) => credentials,
+                    None => Credentials::default_chain()?,
+                };
+                if self.descriptors.values().any(|(setup, _)| setup.region().is_some_and(|r| r != self.region)) {
+                    Box::new(MultiRegionBackend::with_credentials(&self.region, credentials)?) as Box<dyn Backend>
+                } else {
+                    Box::new(Ec2Backend::with_credentials(&self.region, credentials)?)
+                }
             }
+        };
+        if let Some(ref path) = self.ledger {
+            backend.set_ledger(Ledger::open(path)?);
+        }
+        if let Some(key_pair) = self.key_pair.take() {
+            backend.set_key_pair(key_pair)?;
+        }
+        let mut tags = vec![(RUN_ID_TAG.to_string(), self.run_id.clone())];
+        tags.extend(self.tags.iter().filter(|(k, _)| k != RUN_ID_TAG).cloned());
+        backend.set_tags(&tags);
+        let max_duration = self.max_duration;
+        backend.set_max_duration(max_duration);
+
+        info!(log, "spinning up tusnami"; "run" => &self.run_id);
+        // clones of every session, to stop the processes spawned through them however the routines end
+        let sessions = Sessions::default();
+        let log_dir = self.log_dir.clone();
+        // the backend records everything it created as it goes, so it can still be torn down after a panic
+        let result = AssertUnwindSafe(tokio::time::timeout(max_duration, self.run_on(&mut *backend, f, sessions.clone())))
+            .catch_unwind()
+            .await
+            .map(|res| res.unwrap_or_else(|_| {
+                crit!(log, "run exceeded its max duration; aborting");
+                Err(failure::format_err!("run did not finish within its max duration of {:?}", max_duration))
+            }));
+        if let Err(ref e) = result {
+            crit!(log, "run panicked: {}", panic_message(e));
         }
 
-        /*
-         * Following code iterates over all the ec2 requests and checks whether if any one of the request is in open state.
-         * If anyone of them is in "open state", it loops over again and again
-         * If none of them is in "open state", then it collects the instance ids and breaks from the loop
-         */
-        let mut req = rusoto_ec2::DescribeSpotInstanceRequestsRequest::default();
-        req.spot_instance_request_ids = Some(spot_req_ids);
-        let instances: Vec<_>;
-        let mut all_active;
-        debug!(log, "waiting for instances to spwan");
-        loop {
-            trace!(log, "checking spot request status");
-            let res = ec2.describe_spot_instance_requests(req.clone()).await;
-            if let Err(e) = res {
-                let msg = format!("{}",e);
-                if msg.contains("The spot instance request ID") && msg.contains("does not exist") {
-                    trace!(log, "spot instance request not yet ready");
-                    continue;
-                }
-                else {
-                    return Err(e).context("falied to describe spot instances")?;
-                }
-            }
-            let res = res.expect("Error check above");
-            if let Some(spot_instance_requests) = res.spot_instance_requests {
-                // Handle spot_requests.
-                let any_pending = spot_instance_requests
-                                        .iter()
-                                        .map(|sir| (sir, sir.state.as_ref().expect("spot request does not have state specified")))
-                                        .any(|(sir, state)| {
-                                            if state == "open" ||  (state == "active" && sir.instance_id.is_none()) {
-                                                true
-                                            }
-                                            else {
-                                                trace!(log, "spot instance request not yet ready"; "state" => state, "id" => &sir.spot_instance_request_id);
-                                                false
-                                            }
-                                        });
-            
-                if !any_pending {
-                    all_active = true;
-                    instances = spot_instance_requests
-                                    .into_iter()
-                                    .filter_map(|sir| {
-                                        if sir.state.as_ref().unwrap() == "active" {
-                                            let name = id_to_name
-                                            .remove(&sir.spot_instance_request_id.expect("spot request must have spot request id"))
-                                            .expect("every spot request id is made for some machine set");
-
-                                            let instance_id = sir.instance_id.unwrap();
-                                            trace!(log, "spot request satisfied"; "setup" => &name, "iid" => &instance_id);
-                                            id_to_name.insert(instance_id.clone(), name);
-                                            Some(instance_id)
-                                        }
-                                        else {
-                                            all_active=false;
-                                            None
-                                        }
-                                    })
-                                    .collect();
-                    break;
-                }
-                else {                        
-                }
-            } 
+        // collecting runs on a thread of its own and is given up on after COLLECT_LIMIT, since routines abandoned
+        // at max_duration may still be using the sessions, and nothing may keep teardown from running
+        let sessions = std::mem::take(&mut *sessions.lock().unwrap());
+        let (collected, done) = tokio::sync::oneshot::channel();
+        let collect_log = log.clone();
+        std::thread::spawn(move || {
+            collect_spawned(&collect_log, log_dir.as_deref(), sessions);
+            let _ = collected.send(());
+        });
+        if tokio::time::timeout(COLLECT_LIMIT, done).await.is_err() {
+            crit!(log, "gave up on collecting spawned processes"; "after" => ?COLLECT_LIMIT);
         }
 
-        let mut term_instances  = instances.clone();
-        let ec2_clone  = ec2.clone();
-        let log_clone  = log.clone();
-        defer!{{
-            /***
-             * Lastly ec2 remote instance termination request is executed  to stop all the instances started.
-             */
-            debug!(log_clone, "terminating instances");
-            let executor = Handle::current();
-            let mut termination_req = rusoto_ec2::TerminateInstancesRequest::default();
-            termination_req.instance_ids = mem::replace(&mut term_instances, Vec::new());
-            executor.spawn({
-                async move{
-                    while let Err(e) = ec2_clone.terminate_instances(termination_req.clone()).await {
-                        let msg = format!("{}", e);
-                        if msg.contains("Pooled stream disconnected") || msg.contains("broken pipe") {
-                            trace!(log_clone, "retrying instance termination");
-                            continue
-                        }
-                        else {
-                            warn!(log_clone, "failed to terminate instances : {:?}", e);
-                        }
-                    }
-        
-                    //debug!(log, "cleaning up temporary resources");
-                    //trace!(log, "cleaning up terminating security group");
-                    // let mut req = rusoto_ec2::DeleteSecurityGroupRequest::default();
-                    // req.group_id = Some(group_id);
-                    
-                    // ec2.delete_security_group(req).await.context("failed to clean secuity group")?;
-                    
-                    //trace!(log, "cleaning up terminating keypair");
-                    // let mut req = rusoto_ec2::DeleteKeyPairRequest::default();
-                    // req.key_name = Some(key_name);
-                    // ec2.delete_key_pair(req).await.context("failed to clean key pair")?;
-                }
-            });
-        }}
-
-
-        /*
-        * Here once all the ec2 spot instance requests are satified, the instances are now starting or runing.
-        * The spot instance requests are cancelled, to ensure that if anyone of the instances stops, the spot instance requests are not called again.
-        * All the requests happen once and all the instances are requested/started only once.
-        */
-        trace!(log, "terminating spot requests");
-        let mut cancel = rusoto_ec2::CancelSpotInstanceRequestsRequest::default();
-        cancel.spot_instance_request_ids = req.spot_instance_request_ids.expect("this is set above");
-        ec2.cancel_spot_instance_requests(cancel).await
-        .context("falied to cancel spot instance request").map_err(|e| {
-            warn!(log, "failed to cancel sopt instance requests: {:?}", e);
-            e
-        })?;
+        debug!(log, "tearing down");
+        let leftovers = backend.teardown(&log).await;
+        if leftovers.is_empty() {
+            info!(log, "teardown complete");
+        }
+        for resource in &leftovers {
+            crit!(log, "teardown left {} behind", resource);
+        }
 
+        debug!(log, "all done");
+        let result = result.unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        if leftovers.is_empty() {
+            result
+        } else {
+            Err(TeardownIncomplete { leftovers, run_error: result.err() }.into())
+        }
+    }
 
-        /****
-         * Here all the ec2 instances which are requested are iterated and checked where 
-         * if all the requested ec2 machines are ready or not
-         * it all not ready, then status of all the instances are requested again and checked
-         * if all ready, then Machine structs are are populated with the config of the ec2 machines and stored in machines vector. 
-         */
-        let mut machines = HashMap::new();
-        let mut desc_req: rusoto_ec2::DescribeInstancesRequest = rusoto_ec2::DescribeInstancesRequest::default();
-        let mut all_ready = false;
-        while !all_ready {
-            machines.clear();
-            all_ready = true;
-            desc_req.instance_ids = Some(instances.clone());
-            let res: rusoto_ec2::DescribeInstancesResult = ec2.describe_instances(desc_req.clone()).await
-                                                                    .map_err(Error::from)
-                                                                    .map_err(|e| e.context("falied to cancel spot instance request"))?;
-            if let Some(res_reservations) = res.reservations {
-                for reservations in res_reservations.into_iter() {
-                    for instance in reservations.instances.unwrap_or_else(Vec::new) {
-                        match instance {
-                            rusoto_ec2::Instance {
-                                instance_id: Some(instance_id),
-                                instance_type: Some(instance_type),
-                                private_ip_address: Some(private_ip),
-                                public_dns_name: Some(public_dns),
-                                public_ip_address: Some(public_ip),
-                                ..
-                            } => {
-                                let machine = Machine{
-                                    ssh:None,
-                                    instance_type,
-                                    private_ip,
-                                    public_dns,
-                                    public_ip
-                                };
-                                let name = id_to_name[&instance_id].clone();
-                                trace!(log, "instance ready"; "set" => &name, "ip"=> &machine.public_ip);
-                                machines.entry(name).or_insert_with(Vec::new).push(machine);
-                            }
-                            _=> { 
-                                all_ready = false;
-                            }
-                        }
-                    }
-                }
-                
-            } else {
-                // Handle the case when spot_instance_requests is None.
-            }
-            
+    async fn run_on<F>(self, backend: &mut dyn Backend, f: F, sessions: Sessions) -> Result<(), Error>
+    where F: FnOnce(HashMap<String, Vec<Machine>>) -> Result<(), Error> + Send + 'static
+    {
+        let machines = backend.provision(&self.log, &self.descriptors).await?;
+
+        // without a private key file the ssh-agent holds the key
+        let private_key = backend.private_key().filter(|_| !self.ssh_agent).map(Path::to_path_buf);
+        let user = backend.ssh_user().to_string();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        std::thread::spawn(move || {
+            let res = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                self.setup_and_run(machines, private_key.as_deref(), &user, f, &sessions)
+            }));
+            // nobody is listening anymore if the run was aborted
+            let _ = tx.send(res);
+        });
+        match rx.await {
+            Ok(Ok(res)) => res,
+            Ok(Err(panic)) => std::panic::resume_unwind(panic),
+            Err(_) => Err(failure::err_msg("setup and main routines vanished without a result")),
         }
-        // req.spot_instance_request_ids = 
-        // ec 2.describe_spot_instance_requests(req)
+    }
+
+    fn setup_and_run<F>(
+        &self,
+        mut machines: HashMap<String, Vec<Machine>>,
+        private_key: Option<&Path>,
+        user: &str,
+        f: F,
+        sessions: &Mutex<Vec<(String, String, ssh::Session)>>,
+    ) -> Result<(), Error>
+    where F: FnOnce(HashMap<String, Vec<Machine>>) -> Result<(), Error>
+    {
+        let log = &self.log;
+        let command_timeout = self.command_timeout;
+
         /***
          * Here for all the machines which are up and running,
-         * one by one ssh connection is established to each of the remote ec2 machines  and certain commands are executed to verify if they are running properly.
-         * First a tcp stream to the ssh server in the remote ec2 machine is established
+         * one by one ssh connection is established to each of the remote machines and the setup routine of its machine set is executed.
+         * First a tcp stream to the ssh server in the remote machine is established
          * then a ssh session is created using ssh2 crate, the tcp stream is associated with the sssh session, which will enabled the ssh session to connect to remote machine using tcp stream
          * finally a ssh handshake happens to initialize ssh session and it negotiates encryptin and other settings
-         * finally authentication happens with ssh user agent authentication method 
-         * Lastly a ssh channel is created for executing commands in the remote server and the output is also recieved & printed on local machine
+         * finally authentication happens with the private key provided by the backend
+         * once every machine of a set is connected, the artifacts of the set are deployed to all of them,
+         * and then the setup routine runs on each; the sessions are kept in Machine::ssh for the main routine
          */
+        info!(log, "all machines instantiated; running setup routines");
         let mut errors: Vec<Error> = Vec::new();
-        if all_active
-        {
-            info!(log, "all machines instantiated; running setup routines");
-            for (name,machines) in &mut machines {          
-                let f = &setup_fns[name];  
-                errors.par_extend(
-                    machines
-                                .par_iter_mut()
-                                .map(|machine| -> Result<_, Error>{
-                                    let mut sess = ssh::Session::connect(
-                                        SocketAddr::new(
-                                            machine.public_ip
-                                            .parse::<IpAddr>()
-                                            .context("machine ip is not an ip address")?, 
-                                            22),
-                                        // &format!("{}:22", machine.public_ip),
-                                         private_key_file.path()
-                                        )
-                                    .context(format!(
-                                        "falied to ssh to {} machine {}",
-                                        name,
-                                        machine.public_ip
-                                        )).map_err(|e| {
-                                            error!(log, "failed to ssh to {}:{}", name, machine.public_ip);
-                                            e
-                                        })?;
-
-                                    debug!(log, "setting up {} instance", &name; "ip"=> &machine.public_ip);
-                                    f(&mut sess)
-                                    .context(format!(
-                                        "setup routine for {} machine failed",
-                                        name
-                                    )).map_err(|e| {
-                                        error!(log, "setup for {} machine failed", name);
-                                        e
-                                    })?; 
-                                    info!(log, "finished setting up {} instance", &name; "ip"=> &machine.public_ip);
-                                    Ok(())
-                    }) 
+        for (name, machines) in &mut machines {
+            let setup = &self.descriptors[name].0;
+            let f = &setup.setup;
+            // the user the first machine of the set accepted is tried first on the others
+            let accepted: Mutex<Option<String>> = Mutex::new(None);
+            errors.par_extend(
+                machines
+                    .par_iter_mut()
+                    .map(|machine| -> Result<_, Error> {
+                        let known = accepted.lock().unwrap().clone();
+                        let (mut sess, ssh_user) = ssh::Session::connect_as(
+                            SocketAddr::new(
+                                machine.public_ip
+                                    .parse::<IpAddr>()
+                                    .context("machine ip is not an ip address")?,
+                                machine.ssh_port),
+                            &setup.ssh_users(user, known.as_deref()),
+                            private_key,
+                        )
+                        .context(format!(
+                            "falied to ssh to {} machine {}",
+                            name,
+                            machine.public_ip
+                        ))
+                        .inspect_err(|_| {
+                            error!(log, "failed to ssh to {}:{}", name, machine.public_ip);
+                        })?;
+                        if known.as_ref() != Some(&ssh_user) {
+                            debug!(log, "logged in to {} instance", &name; "ip" => &machine.public_ip, "user" => &ssh_user);
+                            *accepted.lock().unwrap() = Some(ssh_user.clone());
+                        }
+                        machine.ssh_user = ssh_user;
+                        sess.set_logger(log.new(o!("set" => name.clone(), "ip" => machine.public_ip.clone())));
+                        sess.set_timeout(command_timeout);
+                        sessions.lock().unwrap().push((name.clone(), machine.public_ip.clone(), sess.try_clone()?));
+                        machine.ssh = Some(sess);
+                        Ok(())
+                    })
                     .filter_map(Result::err)
-                ); 
+            );
+            if machines.iter().any(|machine| machine.ssh.is_none()) {
+                continue;
             }
-            if errors.is_empty() {
-                let start = time::Instant::now();
-                info!(log, "quiet before storm");
-                f(machines).context("main routi  ne failed").map_err(|e| {
-                    crit!(log, "main tusnami failed");
-                    e
-                })?;
-                info!(log, "power of the tsunami unleashed"; "duration" => start.elapsed().as_secs());
+
+            let fan_out = setup.fan_out.is_some_and(|min| machines.len() >= min as usize);
+            if let Err(e) = deploy::deploy(log, name, machines, &setup.artifacts, fan_out) {
+                error!(log, "failed to deploy artifacts to {} machines", name);
+                errors.push(e.context(format!("failed to deploy artifacts to {} machines", name)).into());
+                continue;
             }
-               
+
+            errors.par_extend(
+                machines
+                    .par_iter_mut()
+                    .map(|machine| -> Result<_, Error> {
+                        let sess = machine.ssh.as_mut().expect("connected above");
+                        debug!(log, "setting up {} instance", &name; "ip" => &machine.public_ip);
+                        f(sess)
+                            .context(format!(
+                                "setup routine for {} machine failed",
+                                name
+                            ))
+                            .inspect_err(|_| {
+                                error!(log, "setup for {} machine failed", name);
+                            })?;
+                        info!(log, "finished setting up {} instance", &name; "ip" => &machine.public_ip);
+                        Ok(())
+                    })
+                    .filter_map(Result::err)
+            );
+        }
+        if let Some(e) = errors.into_iter().next() {
+            return Err(e);
         }
 
-        debug!(log, "all done");
-        errors.into_iter().next().map(|e| Err(e)).unwrap_or(Ok(()))         
+        let start = time::Instant::now();
+        info!(log, "quiet before storm");
+        f(machines).context("main routine failed").inspect_err(|_| {
+            crit!(log, "main tusnami failed");
+        })?;
+        info!(log, "power of the tsunami unleashed"; "duration" => start.elapsed().as_secs());
+        Ok(())
     }
 }
 
+// how long stopping and collecting the spawned processes may hold up teardown
+const COLLECT_LIMIT: time::Duration = time::Duration::from_secs(60);
+
+/*
+ * The sessions of a run, with the set and ip of their machine.
+ */
+type Sessions = Arc<Mutex<Vec<(String, String, ssh::Session)>>>;
+
+/*
+ * Stops the processes spawned on the machines that are still running, and collects their logs
+ * into `log_dir`, or the logger.
+ */
+fn collect_spawned(log: &slog::Logger, log_dir: Option<&Path>, mut sessions: Vec<(String, String, ssh::Session)>) {
+    sessions.par_iter_mut().for_each(|(name, ip, sess)| {
+        let dir = log_dir.map(|dir| dir.join(&*name));
+        debug!(log, "collecting spawned processes"; "set" => &*name, "ip" => &*ip);
+        spawn::collect(sess, dir.as_deref(), ip);
+    });
+}
+
+/*
+ * Tag key of the run id, see BurstBuilder::run_id.
+ */
+pub const RUN_ID_TAG: &str = "burst:run-id";
+
+/*
+ * A random (version 4) UUID.
+ */
+fn new_run_id() -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+/*
+ * The function "reap" destroys every EC2 resource that the ledger at `path` records as created but not released,
+ * i.e. whatever earlier runs that crashed or were killed left behind, in whichever region each was created in.
+ * Releases are recorded in the same ledger, so reaping twice is harmless. Returns the resources that were reaped.
+ */
+#[tokio::main]
+pub async fn reap(path: &Path) -> Result<Vec<Resource>, Error> {
+    let credentials = Credentials::default_chain()?;
+    reap_ledger(path, move |region| Ec2Backend::with_credentials(region, credentials.clone())).await
+}
+
+/*
+ * The function "reap_tagged" destroys every EC2 resource in `region` tagged with `key` = `value`,
+ * for when there is no ledger to go by. Returns the resources that were reaped.
+ */
+#[tokio::main]
+pub async fn reap_tagged(region: &str, key: &str, value: &str) -> Result<Vec<Resource>, Error> {
+    let mut backend = Ec2Backend::in_region(region)?;
+    let resources = backend.find_tagged(key, value).await?;
+    reap_with(&mut backend, resources).await
+}
+
+/*
+ * Reaps what the ledger at `path` has outstanding through one backend per region, got from `connect`.
+ */
+async fn reap_ledger<C, F>(path: &Path, connect: F) -> Result<Vec<Resource>, Error>
+where C: Ec2Api, F: Fn(&str) -> Result<Ec2Backend<C>, Error>
+{
+    let mut by_region: Vec<(String, Vec<Resource>)> = Vec::new();
+    for (region, resource) in Ledger::open(path)?.outstanding()? {
+        match by_region.iter_mut().find(|(r, _)| *r == region) {
+            Some((_, resources)) => resources.push(resource),
+            None => by_region.push((region, vec![resource])),
+        }
+    }
+    let mut backends = Vec::new();
+    for (region, resources) in by_region {
+        let mut backend = connect(&region).context(format!("failed to connect to {}", region))?;
+        backend.set_ledger(Ledger::open(path)?);
+        backends.push((backend, resources));
+    }
+
+    let log = slog::Logger::root(slog::Discard, o!());
+    let mut reaped = Vec::new();
+    let mut leftovers = Vec::new();
+    for (mut backend, resources) in backends {
+        leftovers.extend(backend.reap(&log, resources.clone()).await);
+        reaped.extend(resources);
+    }
+    if !leftovers.is_empty() {
+        return Err(TeardownIncomplete { leftovers, run_error: None }.into());
+    }
+    Ok(reaped)
+}
+
+async fn reap_with<C: Ec2Api>(backend: &mut Ec2Backend<C>, resources: Vec<Resource>) -> Result<Vec<Resource>, Error> {
+    let log = slog::Logger::root(slog::Discard, o!());
+    let leftovers = backend.reap(&log, resources.clone()).await;
+    if !leftovers.is_empty() {
+        return Err(TeardownIncomplete { leftovers, run_error: None }.into());
+    }
+    Ok(resources)
+}
+
+fn panic_message(panic: &Box<dyn Any + Send>) -> &str {
+    if let Some(msg) = panic.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = panic.downcast_ref::<String>() {
+        msg
+    } else {
+        "unknown panic"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    /*
+     * Backend without machines that can be told to panic while provisioning.
+     */
+    struct StubBackend {
+        panic_in_provision: bool,
+        torn_down: Arc<AtomicBool>,
+    }
+
+    #[async_trait::async_trait]
+    impl Backend for StubBackend {
+        async fn provision(
+            &mut self,
+            _: &slog::Logger,
+            _: &HashMap<String, (MachineSetup, u32)>,
+        ) -> Result<HashMap<String, Vec<Machine>>, Error> {
+            assert!(!self.panic_in_provision, "provisioning blew up");
+            Ok(HashMap::new())
+        }
+
+        fn private_key(&self) -> Option<&Path> {
+            Some(Path::new("/nonexistent"))
+        }
+
+        fn ssh_user(&self) -> &str {
+            "nobody"
+        }
+
+        async fn teardown(&mut self, _: &slog::Logger) -> Vec<Resource> {
+            self.torn_down.store(true, Ordering::SeqCst);
+            Vec::new()
+        }
+    }
+
+    fn stub_builder(panic_in_provision: bool) -> (BurstBuilder, Arc<AtomicBool>) {
+        let torn_down = Arc::new(AtomicBool::new(false));
+        let mut b = BurstBuilder::default();
+        b.set_backend(StubBackend { panic_in_provision, torn_down: torn_down.clone() });
+        (b, torn_down)
+    }
+
+    fn fake_backend(fake: &FakeEc2) -> Ec2Backend<FakeEc2> {
+        let mut backend = Ec2Backend::with_client("us-east-1", fake.clone()).unwrap();
+        backend.set_backoff(crate::poll::TEST_BACKOFF);
+        backend
+    }
+
+    #[test]
+    fn reap_destroys_what_a_crashed_run_left_behind() {
+        let us = FakeEc2::new("us-east-1");
+        let eu = FakeEc2::new("eu-west-1");
+        us.set_capacity("t3.small", 1);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ledger");
+        let log = slog::Logger::root(slog::Discard, o!());
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        // a run that dies after provisioning never gets to tear down, only its spot requests were cancelled
+        let mut backend = fake_backend(&us);
+        backend.set_ledger(Ledger::open(&path).unwrap());
+        let mut sets = HashMap::new();
+        sets.insert("client".to_string(), (MachineSetup::new("t3.small", "ami-e18aa89b", |_| Ok(())), 2));
+        assert!(rt.block_on(backend.provision(&log, &sets)).is_err());
+        drop(backend);
+
+        // and so does one in another region
+        let mut backend = Ec2Backend::with_client("eu-west-1", eu.clone()).unwrap();
+        backend.set_backoff(crate::poll::TEST_BACKOFF);
+        backend.set_ledger(Ledger::open(&path).unwrap());
+        let mut sets = HashMap::new();
+        sets.insert("server".to_string(), (MachineSetup::new("t3.small", "ami-0bdf93799014acdc4", |_| Ok(())), 1));
+        rt.block_on(backend.provision(&log, &sets)).unwrap();
+        drop(backend);
+
+        let outstanding = Ledger::open(&path).unwrap().outstanding().unwrap();
+        assert_eq!(outstanding.iter().filter(|(region, _)| region == "us-east-1").count(), 3, "{:?}", outstanding);
+        assert_eq!(outstanding.iter().filter(|(region, _)| region == "eu-west-1").count(), 3, "{:?}", outstanding);
+
+        let fakes: HashMap<&str, FakeEc2> = vec![("us-east-1", us.clone()), ("eu-west-1", eu.clone())].into_iter().collect();
+        let reaped = rt.block_on(reap_ledger(&path, |region| {
+            let mut backend = Ec2Backend::with_client(region, fakes[region].clone())?;
+            backend.set_backoff(crate::poll::TEST_BACKOFF);
+            Ok(backend)
+        })).unwrap();
+        assert_eq!(reaped.len(), 6);
+        for fake in &[&us, &eu] {
+            assert!(fake.security_groups().is_empty());
+            assert!(fake.key_pairs().is_empty());
+            assert_eq!(fake.instances_in_state("terminated").len(), 1);
+        }
+        assert!(Ledger::open(&path).unwrap().outstanding().unwrap().is_empty());
+    }
+
+    #[test]
+    fn ssh_agent_needs_a_key_pair_it_can_hold() {
+        let (mut b, torn_down) = stub_builder(false);
+        b.use_ssh_agent();
+        let err = b.run(|_| Ok(())).unwrap_err();
+        assert!(err.to_string().contains("use_ssh_agent"), "{}", err);
+        assert!(!torn_down.load(Ordering::SeqCst));
+
+        let (mut b, _) = stub_builder(false);
+        b.use_ssh_agent();
+        b.set_key_pair(KeyPair::Generated);
+        assert!(b.run(|_| Ok(())).is_err());
+
+        let (mut b, _) = stub_builder(false);
+        b.use_ssh_agent();
+        b.set_key_pair(KeyPair::Existing { name: "experiments".to_string(), private_key: None });
+        b.run(|_| Ok(())).unwrap();
+    }
+
+    #[test]
+    fn run_tears_down_after_main_routine_fails() {
+        let (b, torn_down) = stub_builder(false);
+        let res = b.run(|_| Err(failure::err_msg("experiment failed")));
+        assert!(res.is_err());
+        assert!(torn_down.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn run_tears_down_before_resuming_panics() {
+        let (b, torn_down) = stub_builder(true);
+        let res = std::panic::catch_unwind(AssertUnwindSafe(|| b.run(|_| Ok(()))));
+        assert!(res.is_err());
+        assert!(torn_down.load(Ordering::SeqCst));
+
+        let (b, torn_down) = stub_builder(false);
+        let res = std::panic::catch_unwind(AssertUnwindSafe(|| b.run(|_| panic!("main routine blew up"))));
+        assert!(res.is_err());
+        assert!(torn_down.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn run_tears_down_when_provisioning_fails() {
+        let fake = FakeEc2::new("us-east-1");
+        fake.set_capacity("t3.small", 2);
+
+        let mut b = BurstBuilder::default();
+        b.set_backend(fake_backend(&fake));
+        b.add_set("client", 3, MachineSetup::new("t3.small", "ami-e18aa89b", |_| Ok(())));
+        let res = b.run(|_| panic!("main routine must not run without machines"));
+
+        assert!(res.unwrap_err().downcast_ref::<TeardownIncomplete>().is_none());
+        assert_eq!(fake.instances_in_state("terminated").len(), 2);
+        assert!(fake.security_groups().is_empty());
+        assert!(fake.key_pairs().is_empty());
+    }
+
+    #[test]
+    fn run_tags_resources_with_run_id_and_owner() {
+        let fake = FakeEc2::new("us-east-1");
+        fake.set_capacity("t3.small", 1);
+
+        let mut b = BurstBuilder::default();
+        b.set_backend(fake_backend(&fake));
+        b.add_tag("owner", "alice");
+        b.add_tag("owner", "bob");
+        b.add_set("client", 2, MachineSetup::new("t3.small", "ami-e18aa89b", |_| Ok(())));
+        let run_id = b.run_id().to_string();
+        assert_eq!(run_id.len(), 36);
+        assert!(b.run(|_| Ok(())).is_err());
+
+        let instance = fake.instances_in_state("terminated").pop().expect("one instance was launched");
+        let tags = fake.tags(&instance);
+        assert_eq!(tags[RUN_ID_TAG], run_id);
+        assert_eq!(tags["owner"], "bob");
+        assert_eq!(tags[SET_TAG], "client");
+        assert_eq!(tags[INDEX_TAG], "0");
+    }
+
+    #[test]
+    fn run_aborts_main_routine_after_max_duration() {
+        let (mut b, torn_down) = stub_builder(false);
+        b.max_duration = time::Duration::from_millis(100);
+        let start = time::Instant::now();
+        // ends on its own soon after the deadline, rather than outlive the test
+        let res = b.run(|_| {
+            std::thread::sleep(time::Duration::from_millis(300));
+            Ok(())
+        });
+        assert!(res.unwrap_err().to_string().contains("max duration"));
+        assert!(start.elapsed() < time::Duration::from_secs(10));
+        assert!(torn_down.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn ssh_users_fall_back_to_common_amis() {
+        let mut setup = MachineSetup::new("t3.small", "ami-e18aa89b", |_| Ok(()));
+        assert_eq!(setup.ssh_users("ec2-user", None), ["ec2-user", "ubuntu", "admin", "centos"]);
+        assert_eq!(setup.ssh_users("alice", Some("ubuntu")), ["ubuntu", "alice", "ec2-user", "admin", "centos"]);
+
+        setup.set_ssh_user("debian");
+        assert_eq!(setup.ssh_users("ec2-user", Some("ubuntu")), ["debian"]);
+    }
+}
@@ -0,0 +1,470 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+use async_trait::async_trait;
+use failure::{Error, ResultExt};
+
+use crate::backend::{Backend, Resource};
+use crate::poll::{Backoff, Poll};
+use crate::{Machine, MachineSetup};
+
+/*
+ * LocalBackend runs every "machine" as its own sshd process on localhost, so that MachineSetup closures and
+ * the main routine can be debugged end-to-end on a laptop, through the same ssh sessions as on EC2.
+ * Each machine gets its own port, home directory and generated host key; a single generated client key
+ * is authorized on all of them. Sessions log in as the current user, with the machine's home directory
+ * as working directory and $HOME.
+ * sshd is not started as root, so only the current user can log in and nothing is sandboxed.
+ */
+pub struct LocalBackend {
+    sshd: PathBuf,
+    sftp_server: Option<PathBuf>,
+    user: String,
+    private_key: PathBuf,
+    dir: tempfile::TempDir,
+    processes: Vec<Child>,
+}
+
+const SFTP_SERVERS: &[&str] = &[
+    "/usr/lib/openssh/sftp-server",
+    "/usr/libexec/openssh/sftp-server",
+    "/usr/libexec/sftp-server",
+    "/usr/lib/ssh/sftp-server",
+];
+
+impl LocalBackend {
+    /*
+     * Creates a backend using /usr/sbin/sshd, and generates the client key in a fresh temporary directory.
+     */
+    pub fn new() -> Result<Self, Error> {
+        let dir = tempfile::Builder::new()
+            .prefix("burst_local_")
+            .tempdir()
+            .context("failed to create directory for local machines")?;
+        let private_key = dir.path().join("id_ed25519");
+        keygen(&private_key)?;
+
+        let user = match std::env::var("USER") {
+            Ok(user) => user,
+            Err(_) => {
+                let out = Command::new("id").arg("-un").output().context("failed to look up current user")?;
+                String::from_utf8_lossy(&out.stdout).trim().to_string()
+            }
+        };
+
+        Ok(LocalBackend {
+            sshd: PathBuf::from("/usr/sbin/sshd"),
+            sftp_server: SFTP_SERVERS.iter().map(PathBuf::from).find(|p| p.exists()),
+            user,
+            private_key,
+            dir,
+            processes: Vec::new(),
+        })
+    }
+
+    /*
+     * Sets the sshd binary to run, it has to be an absolute path for sshd to re-execute itself.
+     */
+    pub fn set_sshd(&mut self, sshd: &Path) {
+        self.sshd = sshd.to_path_buf();
+    }
+
+    /*
+     * Starts one sshd for the machine with the given name and returns the port it listens on.
+     */
+    fn start_sshd(&mut self, log: &slog::Logger, name: &str) -> Result<u16, Error> {
+        let dir = self.dir.path().join(name);
+        let home = dir.join("home");
+        fs::create_dir_all(&home).context(format!("failed to create home directory for {}", name))?;
+        keygen(&dir.join("ssh_host_ed25519_key"))?;
+        fs::copy(self.private_key.with_extension("pub"), dir.join("authorized_keys"))
+            .context("failed to authorize client key")?;
+
+        // the kernel hands out a free port, which is released again right before sshd binds it
+        let port = TcpListener::bind("127.0.0.1:0")
+            .and_then(|l| l.local_addr())
+            .context("failed to find a free port")?
+            .port();
+
+        let mut config = format!(
+            "Port {port}\n\
+             ListenAddress 127.0.0.1\n\
+             HostKey {dir}/ssh_host_ed25519_key\n\
+             PidFile {dir}/sshd.pid\n\
+             AuthorizedKeysFile {dir}/authorized_keys\n\
+             PasswordAuthentication no\n\
+             KbdInteractiveAuthentication no\n\
+             UsePAM no\n\
+             StrictModes no\n\
+             ForceCommand cd \"{home}\" && export HOME=\"{home}\" && exec /bin/sh -c \"${{SSH_ORIGINAL_COMMAND:-exec $SHELL -l}}\"\n",
+            port = port,
+            dir = dir.display(),
+            home = home.display(),
+        );
+        if let Some(ref sftp_server) = self.sftp_server {
+            config.push_str(&format!("Subsystem sftp {}\n", sftp_server.display()));
+        }
+        fs::write(dir.join("sshd_config"), config).context("failed to write sshd config")?;
+
+        let log_file = fs::File::create(dir.join("sshd.log")).context("failed to create sshd log")?;
+        trace!(log, "starting sshd"; "machine" => name, "port" => port);
+        let child = Command::new(&self.sshd)
+            .arg("-D")
+            .arg("-e")
+            .arg("-f")
+            .arg(dir.join("sshd_config"))
+            .stdin(Stdio::null())
+            .stdout(log_file.try_clone().context("failed to share sshd log")?)
+            .stderr(log_file)
+            .spawn()
+            .context(format!("failed to start {}", self.sshd.display()))?;
+        self.processes.push(child);
+        Ok(port)
+    }
+
+    /*
+     * Waits until the last started sshd accepts connections, failing early if it exited instead.
+     */
+    async fn wait_for_sshd(&mut self, name: &str, port: u16) -> Result<(), Error> {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let mut poll = Poll::new(Backoff {
+            initial: Duration::from_millis(20),
+            max: Duration::from_millis(200),
+            deadline: Duration::from_secs(10),
+        }, "sshd to listen");
+        let child = self.processes.last_mut().expect("sshd was just started");
+        loop {
+            if TcpStream::connect_timeout(&addr, Duration::from_millis(100)).is_ok() {
+                return Ok(());
+            }
+            if let Some(status) = child.try_wait().context("failed to check on sshd")? {
+                let output = fs::read_to_string(self.dir.path().join(name).join("sshd.log")).unwrap_or_default();
+                return Err(failure::format_err!("sshd for {} exited with {}: {}", name, status, output.trim()));
+            }
+            poll.wait().await.context(format!("sshd for {} never started listening on port {}", name, port))?;
+        }
+    }
+}
+
+/*
+ * Reads the public half of the ed25519 key at `path`, generating the key there first if there is none.
+ */
+pub(crate) fn public_key(path: &Path) -> Result<String, Error> {
+    if !path.exists() {
+        keygen(path)?;
+    }
+    let mut public_key = path.as_os_str().to_owned();
+    public_key.push(".pub");
+    let public_key = fs::read_to_string(&public_key)
+        .context(format!("failed to read the public key of {}", path.display()))?;
+    Ok(public_key.trim().to_string())
+}
+
+pub(crate) fn keygen(path: &Path) -> Result<(), Error> {
+    let out = Command::new("ssh-keygen")
+        .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+        .arg(path)
+        .output()
+        .context("failed to run ssh-keygen")?;
+    if !out.status.success() {
+        return Err(failure::format_err!(
+            "ssh-keygen failed: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl Backend for LocalBackend {
+    async fn provision(
+        &mut self,
+        log: &slog::Logger,
+        sets: &HashMap<String, (MachineSetup, u32)>,
+    ) -> Result<HashMap<String, Vec<Machine>>, Error> {
+        let mut machines = HashMap::new();
+        for (name, (setup, number)) in sets {
+            for i in 0..*number {
+                let machine_name = format!("{}-{}", name, i);
+                let port = self.start_sshd(log, &machine_name)?;
+                self.wait_for_sshd(&machine_name, port).await?;
+                debug!(log, "local machine ready"; "set" => name, "port" => port);
+                machines.entry(name.clone()).or_insert_with(Vec::new).push(Machine {
+                    ssh: None,
+                    ssh_user: self.user.clone(),
+                    instance_type: setup.instance_type().to_string(),
+                    availability_zone: "local".to_string(),
+                    private_ip: "127.0.0.1".to_string(),
+                    public_dns: "localhost".to_string(),
+                    public_ip: "127.0.0.1".to_string(),
+                    ssh_port: port,
+                });
+            }
+        }
+        Ok(machines)
+    }
+
+    fn private_key(&self) -> Option<&Path> {
+        Some(&self.private_key)
+    }
+
+    fn ssh_user(&self) -> &str {
+        &self.user
+    }
+
+    async fn teardown(&mut self, log: &slog::Logger) -> Vec<Resource> {
+        debug!(log, "stopping local machines");
+        let mut leftovers = Vec::new();
+        for mut child in self.processes.drain(..) {
+            // sshd may already be gone, in which case there is nothing left to stop
+            let _ = child.kill();
+            if let Err(e) = child.wait() {
+                warn!(log, "failed to reap sshd: {}", e);
+                leftovers.push(Resource::Process(child.id()));
+            }
+        }
+        leftovers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::panic::AssertUnwindSafe;
+    use std::sync::{Arc, Mutex};
+    use crate::spawn::is_gone;
+    use crate::{BurstBuilder, CommandFailed, CommandTimeout, Probe, Stream, WaitTimeout};
+
+    /*
+     * Runs `f` on one local machine of the set "server", through BurstBuilder::run like a real run.
+     * The tests that use it need sshd (and sftp-server for transfers), so they only run with --ignored.
+     */
+    fn run_local<F>(configure: impl FnOnce(&mut BurstBuilder), f: F) -> Result<(), Error>
+    where F: FnOnce(&mut crate::ssh::Session) -> Result<(), Error> + Send + 'static
+    {
+        let mut b = BurstBuilder::default();
+        b.set_backend(LocalBackend::new()?);
+        b.add_set("server", 1, MachineSetup::new("local", "none", |_| Ok(())));
+        configure(&mut b);
+        b.run(move |mut machines| {
+            let machine = machines.get_mut("server").and_then(|m| m.first_mut()).expect("server machine");
+            f(machine.ssh.as_mut().expect("session to server"))
+        })
+    }
+
+    #[test]
+    #[ignore = "needs sshd"]
+    fn captures_exit_status_and_output() {
+        run_local(|_| {}, |sess| {
+            let out = sess.exec("echo out; echo err >&2; exit 3")?;
+            assert_eq!((out.status, out.stdout.as_str(), out.stderr.as_str()), (3, "out\n", "err\n"));
+            assert!(sess.exec("true")?.success());
+            let err = sess.exec_checked("echo broken >&2; false").unwrap_err();
+            let failed = err.downcast_ref::<CommandFailed>().expect("command failed");
+            assert_eq!(failed.output.status, 1);
+            assert!(err.to_string().contains("broken"), "{}", err);
+            Ok(())
+        }).unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs sshd"]
+    fn streams_output_line_by_line() {
+        run_local(|_| {}, |sess| {
+            let mut lines = Vec::new();
+            let out = sess.stream_with("echo one; sleep 0.2; echo two >&2; printf three", |stream, line| {
+                lines.push((stream, line.to_string()));
+            })?;
+            assert_eq!(lines, vec![
+                (Stream::Stdout, "one".to_string()),
+                (Stream::Stderr, "two".to_string()),
+                (Stream::Stdout, "three".to_string()),
+            ]);
+            assert_eq!(out.stdout, "one\nthree");
+            Ok(())
+        }).unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs sshd"]
+    fn kills_commands_that_time_out() {
+        run_local(|b| b.set_command_timeout(Duration::from_secs(30)), |sess| {
+            sess.set_timeout_signal(Some("KILL"));
+            let err = sess.exec_timeout("echo $$ > timed-out.pid; sleep 30", Duration::from_millis(500)).unwrap_err();
+            let timeout = err.downcast_ref::<CommandTimeout>().expect("command timeout");
+            assert_eq!(timeout.timeout, Duration::from_millis(500));
+            // the shell and its sleep are gone, rather than left running until they next write
+            sess.exec_checked(
+                "for i in $(seq 50); do kill -0 $(cat timed-out.pid) 2>/dev/null || exit 0; sleep 0.1; done; exit 1"
+            )?;
+            // commands without their own timeout fall back on the session's
+            assert_eq!(sess.timeout(), Some(Duration::from_secs(30)));
+            Ok(())
+        }).unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs sshd"]
+    fn spawned_processes_are_collected_at_the_end_of_the_run() {
+        let logs = tempfile::tempdir().unwrap();
+        let pid = Arc::new(Mutex::new(None));
+        let spawned = pid.clone();
+        let log_dir = logs.path().to_path_buf();
+        run_local(move |b| b.set_log_dir(&log_dir), move |sess| {
+            let finished = sess.spawn("echo done; exit 4")?;
+            assert_eq!(finished.wait(sess)?, 4);
+            assert_eq!(finished.logs(sess)?.stdout, "done\n");
+
+            let server = sess.spawn("echo started; exec sleep 600")?;
+            assert!(server.is_running(sess)?);
+            *spawned.lock().unwrap() = Some(server.pid);
+            Ok(())
+        }).unwrap();
+
+        let pid = pid.lock().unwrap().expect("spawned process");
+        let stdout = fs::read_to_string(logs.path().join("server").join(format!("127.0.0.1-{}.stdout", pid))).unwrap();
+        assert_eq!(stdout, "started\n");
+        assert!(is_gone(pid), "spawned process {} outlived the run", pid);
+    }
+
+    #[test]
+    #[ignore = "needs sshd"]
+    fn spawned_processes_are_collected_when_the_main_routine_panics() {
+        let logs = tempfile::tempdir().unwrap();
+        let pids = Arc::new(Mutex::new(Vec::new()));
+        let spawned = pids.clone();
+        let log_dir = logs.path().to_path_buf();
+        let res = std::panic::catch_unwind(AssertUnwindSafe(move || {
+            run_local(move |b| b.set_log_dir(&log_dir), move |sess| {
+                for cmd in ["echo first; exec sleep 600", "echo second; exec sleep 600"] {
+                    let process = sess.spawn(cmd)?;
+                    spawned.lock().unwrap().push(process.pid);
+                }
+                panic!("main routine blew up");
+            })
+        }));
+        assert!(res.is_err());
+
+        for (pid, line) in pids.lock().unwrap().iter().zip(["first\n", "second\n"]) {
+            let stdout = fs::read_to_string(logs.path().join("server").join(format!("127.0.0.1-{}.stdout", pid))).unwrap();
+            assert_eq!(stdout, line);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs sshd"]
+    fn tears_down_when_the_main_routine_is_stuck_in_a_session_past_max_duration() {
+        let port = Arc::new(Mutex::new(None));
+        let seen = port.clone();
+        let start = std::time::Instant::now();
+        let res = run_local(|b| b.max_duration = Duration::from_secs(1), move |sess| {
+            let addr = sess.exec_checked("echo $SSH_CONNECTION")?.stdout;
+            *seen.lock().unwrap() = addr.split_whitespace().nth(3).map(str::to_string);
+            sess.spawn("exec sleep 600")?;
+            sess.exec("sleep 20")?;
+            Ok(())
+        });
+        assert!(res.unwrap_err().to_string().contains("max duration"));
+        assert!(start.elapsed() < Duration::from_secs(90), "took {:?}", start.elapsed());
+
+        // the machine's sshd is gone, so teardown ran
+        let port: u16 = port.lock().unwrap().clone().expect("sshd port").parse().unwrap();
+        assert!(TcpStream::connect_timeout(&SocketAddr::from(([127, 0, 0, 1], port)), Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    #[ignore = "needs sshd and sftp-server"]
+    fn sftp_round_trip_keeps_permissions() {
+        let local = tempfile::tempdir().unwrap();
+        let from = local.path().join("from");
+        fs::create_dir_all(from.join("bin")).unwrap();
+        fs::write(from.join("bin").join("tool"), "#!/bin/sh\necho tool\n").unwrap();
+        fs::set_permissions(from.join("bin").join("tool"), fs::Permissions::from_mode(0o750)).unwrap();
+        fs::write(from.join("config"), "port = 9000\n").unwrap();
+        fs::set_permissions(from.join("config"), fs::Permissions::from_mode(0o600)).unwrap();
+
+        let to = local.path().join("to");
+        let (upload, download) = (from.clone(), to.clone());
+        run_local(|_| {}, move |sess| {
+            let home = sess.exec_checked("pwd")?.stdout.trim().to_string();
+            let remote = Path::new(&home).join("deployed");
+            sess.upload_dir(&upload, &remote)?;
+            assert_eq!(sess.exec_checked("stat -c %a deployed/bin/tool deployed/config")?.stdout, "750\n600\n");
+            assert_eq!(sess.exec_checked("deployed/bin/tool")?.stdout, "tool\n");
+            sess.download_dir(&remote, &download)?;
+            Ok(())
+        }).unwrap();
+
+        for (file, mode) in [("bin/tool", 0o750), ("config", 0o600)] {
+            assert_eq!(fs::read(to.join(file)).unwrap(), fs::read(from.join(file)).unwrap());
+            assert_eq!(fs::metadata(to.join(file)).unwrap().permissions().mode() & 0o777, mode, "{}", file);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs sshd, sftp-server and python3"]
+    fn deploys_artifacts_from_machine_to_machine() {
+        let local = tempfile::tempdir().unwrap();
+        let artifact = local.path().join("server");
+        fs::write(&artifact, "#!/bin/sh\necho served\n").unwrap();
+        let mut setup = MachineSetup::new("local", "none", |_| Ok(()));
+        setup.add_artifact(&artifact, "bin/server");
+        setup.set_fan_out(2);
+
+        let mut b = BurstBuilder::default();
+        b.set_backend(LocalBackend::new().unwrap());
+        b.add_set("server", 2, setup);
+        b.run(|mut machines| {
+            for machine in machines.get_mut("server").expect("server machines") {
+                let out = machine.ssh.as_mut().expect("session to server").exec_checked("bin/server")?;
+                assert_eq!(out.stdout, "served\n");
+            }
+            Ok(())
+        }).unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs sshd"]
+    fn probes_through_a_session() {
+        run_local(|_| {}, |sess| {
+            let port = sess.exec_checked("echo $SSH_CONNECTION")?.stdout.split_whitespace().nth(3)
+                .expect("sshd port").parse::<u16>()?;
+            Probe::Port("127.0.0.1".to_string(), port).wait_from(sess, Duration::from_secs(10))?;
+
+            let server = sess.spawn("sleep 0.3; touch ready; echo listening")?;
+            Probe::Command("test -e ready".to_string()).wait_from(sess, Duration::from_secs(10))?;
+            Probe::LogLine(server, "listening".to_string()).wait_from(sess, Duration::from_secs(10))?;
+
+            let err = Probe::Command("false".to_string()).wait_from(sess, Duration::from_millis(300)).unwrap_err();
+            assert!(err.downcast_ref::<WaitTimeout>().is_some(), "{}", err);
+
+            // a check that hangs is cut off at the probe's timeout, and killed with what it started
+            let start = std::time::Instant::now();
+            let hang = Probe::Command("sleep 30 & echo $! > hang.pid; wait".to_string());
+            let err = hang.wait_from(sess, Duration::from_millis(500)).unwrap_err();
+            assert!(err.downcast_ref::<WaitTimeout>().is_some(), "{}", err);
+            assert!(start.elapsed() < Duration::from_secs(5), "waited {:?}", start.elapsed());
+            let pid = sess.exec_checked("cat hang.pid")?.stdout.trim().parse()?;
+            assert!(is_gone(pid), "sleep {} outlived the check", pid);
+            assert_eq!(sess.timeout_signal(), None);
+            Ok(())
+        }).unwrap();
+    }
+
+    #[tokio::test]
+    async fn missing_sshd_fails_provisioning() {
+        let log = slog::Logger::root(slog::Discard, o!());
+        let mut backend = LocalBackend::new().unwrap();
+        backend.set_sshd(Path::new("/nonexistent/sshd"));
+        assert!(backend.private_key().unwrap().exists());
+
+        let mut sets = HashMap::new();
+        sets.insert("server".to_string(), (MachineSetup::new("local", "none", |_| Ok(())), 1));
+        assert!(backend.provision(&log, &sets).await.is_err());
+        assert!(backend.teardown(&log).await.is_empty());
+    }
+}
@@ -0,0 +1,168 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+use failure::{Error, Fail};
+use rand::Rng;
+
+/*
+ * Backoff is how long a wait on a cloud API pauses between two checks, and how long it may take overall.
+ * The pause starts out at `initial` and doubles after every check up to `max`, and each one is shortened
+ * randomly by up to half so that concurrent waits do not hit the API in lockstep.
+ * Once `deadline` has passed since the wait began, it fails with WaitTimeout.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub deadline: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(20),
+            deadline: Duration::from_secs(600),
+        }
+    }
+}
+
+/*
+ * WaitTimeout is the error a wait fails with when what it was waiting for did not happen before its deadline.
+ */
+#[derive(Debug)]
+pub struct WaitTimeout {
+    pub what: String,
+    pub waited: Duration,
+}
+
+impl fmt::Display for WaitTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "gave up waiting for {} after {}s", self.what, self.waited.as_secs())
+    }
+}
+
+impl Fail for WaitTimeout {}
+
+/*
+ * Poll paces a single wait according to a Backoff: the waiting loop calls wait between two checks,
+ * and retry when a check failed.
+ */
+pub(crate) struct Poll {
+    backoff: Backoff,
+    what: String,
+    start: Instant,
+    delay: Duration,
+}
+
+impl Poll {
+    pub(crate) fn new(backoff: Backoff, what: &str) -> Self {
+        Poll {
+            backoff,
+            what: what.to_string(),
+            start: Instant::now(),
+            delay: backoff.initial,
+        }
+    }
+
+    /*
+     * Pauses before the next check, or fails with WaitTimeout if the deadline has passed.
+     */
+    pub(crate) async fn wait(&mut self) -> Result<(), Error> {
+        let waited = self.start.elapsed();
+        if waited >= self.backoff.deadline {
+            return Err(WaitTimeout {
+                what: self.what.clone(),
+                waited,
+            }.into());
+        }
+        let delay = if self.delay.is_zero() {
+            self.delay
+        } else {
+            rand::thread_rng().gen_range(self.delay / 2..=self.delay)
+        };
+        tokio::time::sleep(delay.min(self.backoff.deadline - waited)).await;
+        self.delay = (self.delay * 2).min(self.backoff.max);
+        Ok(())
+    }
+
+    /*
+     * Pauses before retrying a check that failed, if retrying may help: when the API throttled the request
+     * (RequestLimitExceeded), the pause is doubled first, and dropped connections are simply retried.
+     * Any other error is returned.
+     */
+    pub(crate) async fn retry(&mut self, e: Error) -> Result<(), Error> {
+        if is_throttled(&e) {
+            self.delay = (self.delay * 2).min(self.backoff.max);
+            self.wait().await
+        } else if is_transient(&e) {
+            self.wait().await
+        } else {
+            Err(e)
+        }
+    }
+}
+
+/*
+ * Errors caused by the API throttling requests rather than by the request itself.
+ */
+pub(crate) fn is_throttled(e: &Error) -> bool {
+    let msg = format!("{}", e);
+    msg.contains("RequestLimitExceeded") || msg.contains("Throttling")
+}
+
+/*
+ * Errors caused by a dropped connection to the API rather than by the request itself.
+ */
+pub(crate) fn is_transient(e: &Error) -> bool {
+    let msg = format!("{}", e);
+    msg.contains("Pooled stream disconnected") || msg.contains("broken pipe")
+}
+
+/*
+ * Backoff for tests, which never want to wait on the fake API for long.
+ */
+#[cfg(test)]
+pub(crate) const TEST_BACKOFF: Backoff = Backoff {
+    initial: Duration::from_millis(1),
+    max: Duration::from_millis(1),
+    deadline: Duration::from_secs(10),
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn backs_off_until_the_deadline() {
+        let mut poll = Poll::new(Backoff {
+            initial: Duration::from_millis(1),
+            max: Duration::from_millis(4),
+            deadline: Duration::from_millis(50),
+        }, "nothing");
+        let mut delays = Vec::new();
+        let err = loop {
+            delays.push(poll.delay);
+            if let Err(e) = poll.wait().await {
+                break e;
+            }
+        };
+        assert_eq!(delays[..4], [1, 2, 4, 4].map(Duration::from_millis));
+        let timeout = err.downcast_ref::<WaitTimeout>().expect("wait timeout");
+        assert!(timeout.waited >= Duration::from_millis(50));
+        assert_eq!(timeout.to_string(), "gave up waiting for nothing after 0s");
+    }
+
+    #[tokio::test]
+    async fn retries_throttled_and_dropped_requests_only() {
+        let mut poll = Poll::new(Backoff {
+            initial: Duration::from_millis(1),
+            max: Duration::from_millis(100),
+            deadline: Duration::from_secs(10),
+        }, "nothing");
+        poll.retry(failure::err_msg("RequestLimitExceeded: Request limit exceeded.")).await.unwrap();
+        assert_eq!(poll.delay, Duration::from_millis(4));
+        poll.retry(failure::err_msg("Pooled stream disconnected")).await.unwrap();
+        assert_eq!(poll.delay, Duration::from_millis(8));
+        assert!(poll.retry(failure::err_msg("UnauthorizedOperation: no")).await.is_err());
+    }
+}
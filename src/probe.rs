@@ -0,0 +1,269 @@
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use failure::{Error, ResultExt};
+
+use crate::poll::WaitTimeout;
+use crate::spawn::Process;
+use crate::ssh::{quote, CommandTimeout, Session};
+
+// how long a single check of a port or url may take; commands may take up to the probe's timeout
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/*
+ * Probe is something that tells whether a machine is ready, e.g. whether the server started on it listens.
+ * Port: a tcp connection to host:port is accepted
+ * Http: a GET of the url (plain http only) answers with status 200
+ * Command: the command exits with status 0
+ * LogLine: a line of what the spawned process wrote to stdout or stderr contains the text
+ *
+ * A probe is either checked from here (wait), or through the session to a machine (wait_from),
+ * e.g. that of a client, to check the server's private address. Commands then run on that machine,
+ * and log lines can only be checked through the session to the machine the process was spawned on.
+ */
+#[derive(Debug, Clone)]
+pub enum Probe {
+    Port(String, u16),
+    Http(String),
+    Command(String),
+    LogLine(Process, String),
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Probe::Port(ref host, port) => write!(f, "port {}:{}", host, port),
+            Probe::Http(ref url) => write!(f, "{} to answer", url),
+            Probe::Command(ref cmd) => write!(f, "command '{}' to succeed", cmd),
+            Probe::LogLine(ref process, ref text) => write!(f, "'{}' in the logs of '{}'", text, process.cmd),
+        }
+    }
+}
+
+impl Probe {
+    /*
+     * Checks the probe from here until it succeeds, failing with WaitTimeout after `timeout`.
+     */
+    pub fn wait(&self, timeout: Duration) -> Result<(), Error> {
+        if let Probe::LogLine(ref process, _) = *self {
+            return Err(failure::format_err!(
+                "the logs of '{}' can only be checked through the session it was spawned with", process.cmd
+            ));
+        }
+        self.poll(timeout, |left| self.check(left))
+    }
+
+    /*
+     * Checks the probe through `sess` until it succeeds, failing with WaitTimeout after `timeout`.
+     */
+    pub fn wait_from(&self, sess: &mut Session, timeout: Duration) -> Result<(), Error> {
+        self.poll(timeout, |left| self.check_from(sess, left))
+    }
+
+    /*
+     * Runs `check` until it succeeds or `timeout` has passed, handing it the time left, which no check may outlast.
+     */
+    fn poll<F>(&self, timeout: Duration, mut check: F) -> Result<(), Error>
+    where F: FnMut(Duration) -> Result<bool, Error>
+    {
+        let start = Instant::now();
+        let mut delay = Duration::from_millis(50);
+        loop {
+            if check(timeout.saturating_sub(start.elapsed()))? {
+                return Ok(());
+            }
+            let waited = start.elapsed();
+            if waited >= timeout {
+                return Err(WaitTimeout { what: self.to_string(), waited }.into());
+            }
+            thread::sleep(delay.min(timeout - waited));
+            delay = (delay * 2).min(Duration::from_secs(2));
+        }
+    }
+
+    fn check(&self, left: Duration) -> Result<bool, Error> {
+        match *self {
+            Probe::Port(ref host, port) => Ok(connect(host, port, left.min(CHECK_TIMEOUT)).is_some()),
+            Probe::Http(ref url) => {
+                let (host, port, path) = parse_http_url(url)?;
+                let mut stream = match connect(&host, port, left.min(CHECK_TIMEOUT)) {
+                    Some(stream) => stream,
+                    None => return Ok(false),
+                };
+                let request = format!("GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n", path, host);
+                let mut response = Vec::new();
+                if stream.write_all(request.as_bytes()).and_then(|_| stream.read_to_end(&mut response)).is_err() {
+                    return Ok(false);
+                }
+                let status_line = String::from_utf8_lossy(&response).lines().next().unwrap_or_default().to_string();
+                Ok(status_line.split_whitespace().nth(1) == Some("200"))
+            }
+            Probe::Command(ref cmd) => {
+                // in a process group of its own, so that whatever it started goes down with it
+                let mut child = Command::new("sh")
+                    .arg("-c")
+                    .arg(cmd)
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .process_group(0)
+                    .spawn()
+                    .context(format!("failed to run '{}'", cmd))?;
+                let start = Instant::now();
+                loop {
+                    if let Some(status) = child.try_wait().context(format!("failed to check on '{}'", cmd))? {
+                        return Ok(status.success());
+                    }
+                    let ran = start.elapsed();
+                    if ran >= left {
+                        let _ = Command::new("kill")
+                            .args(["-KILL", "--", &format!("-{}", child.id())])
+                            .stderr(Stdio::null())
+                            .status();
+                        let _ = child.kill();
+                        child.wait().context(format!("failed to stop '{}'", cmd))?;
+                        return Ok(false);
+                    }
+                    thread::sleep((left - ran).min(Duration::from_millis(10)));
+                }
+            }
+            Probe::LogLine(..) => unreachable!("log lines are only checked through sessions"),
+        }
+    }
+
+    fn check_from(&self, sess: &mut Session, left: Duration) -> Result<bool, Error> {
+        let secs = CHECK_TIMEOUT.as_secs();
+        let cmd = match *self {
+            Probe::Port(ref host, port) => format!(
+                "timeout {s} bash -c {} 2>/dev/null || nc -z -w {s} {} {} 2>/dev/null",
+                quote(&format!("</dev/tcp/{}/{}", host, port)),
+                quote(host),
+                port,
+                s = secs
+            ),
+            Probe::Http(ref url) => format!(
+                "if command -v curl >/dev/null; then [ \"$(curl -s -o /dev/null -w '%{{http_code}}' --max-time {s} {u})\" = 200 ]; \
+                 else wget -q -O /dev/null -T {s} {u}; fi",
+                s = secs,
+                u = quote(url)
+            ),
+            Probe::Command(ref cmd) => cmd.clone(),
+            Probe::LogLine(ref process, ref text) => {
+                return match process.logs_timeout(sess, left) {
+                    Ok(logs) => Ok(logs.stdout.lines().chain(logs.stderr.lines()).any(|line| line.contains(text.as_str()))),
+                    Err(ref e) if timed_out(e) => Ok(false),
+                    Err(e) => Err(e),
+                };
+            }
+        };
+        // killed at the timeout together with what it started, as commands checked from here are
+        let signal = sess.timeout_signal().map(str::to_string);
+        sess.set_timeout_signal(Some("KILL"));
+        let res = sess.exec_timeout(&cmd, left);
+        sess.set_timeout_signal(signal.as_deref());
+        match res {
+            Ok(out) => Ok(out.success()),
+            Err(ref e) if timed_out(e) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/*
+ * Whether `e` is, or was caused by, a command that timed out.
+ */
+fn timed_out(e: &Error) -> bool {
+    e.iter_chain().any(|cause| cause.downcast_ref::<CommandTimeout>().is_some())
+}
+
+/*
+ * A tcp connection to host:port, if one can be made within `timeout`, which reads and writes time out after too.
+ */
+fn connect(host: &str, port: u16, timeout: Duration) -> Option<TcpStream> {
+    let addrs = (host, port).to_socket_addrs().ok()?;
+    let stream = addrs.into_iter().find_map(|addr| TcpStream::connect_timeout(&addr, timeout).ok())?;
+    stream.set_read_timeout(Some(timeout)).ok()?;
+    stream.set_write_timeout(Some(timeout)).ok()?;
+    Some(stream)
+}
+
+/*
+ * The host, port and path of an http:// url.
+ */
+fn parse_http_url(url: &str) -> Result<(String, u16, String), Error> {
+    let rest = url.strip_prefix("http://")
+        .ok_or_else(|| failure::format_err!("only http urls can be probed from here, not {}", url))?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse::<u16>().context(format!("bad port in {}", url))?),
+        None => (authority, 80),
+    };
+    Ok((host.to_string(), port, path.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use crate::spawn::is_gone;
+
+    #[test]
+    fn waits_for_ports_and_http_servers() {
+        let open = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = open.local_addr().unwrap().port();
+        Probe::Port("127.0.0.1".to_string(), port).wait(Duration::from_secs(5)).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            for status in ["503 Service Unavailable", "200 OK"] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0u8; 1024];
+                let _ = stream.read(&mut request).unwrap();
+                write!(stream, "HTTP/1.0 {}\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+            }
+        });
+        Probe::Http(format!("http://127.0.0.1:{}/health", port)).wait(Duration::from_secs(5)).unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn gives_up_after_the_timeout() {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let err = Probe::Port("127.0.0.1".to_string(), port).wait(Duration::from_millis(200)).unwrap_err();
+        let timeout = err.downcast_ref::<WaitTimeout>().expect("wait timeout");
+        assert_eq!(timeout.what, format!("port 127.0.0.1:{}", port));
+
+        Probe::Command("true".to_string()).wait(Duration::from_secs(1)).unwrap();
+        assert!(Probe::Command("false".to_string()).wait(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn commands_that_hang_are_killed_at_the_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("pid");
+        let cmd = format!("sleep 30 & echo $! > {}; wait", pid_file.display());
+        let start = Instant::now();
+        let err = Probe::Command(cmd).wait(Duration::from_millis(300)).unwrap_err();
+        assert!(err.downcast_ref::<WaitTimeout>().is_some(), "{}", err);
+        assert!(start.elapsed() < Duration::from_secs(5), "waited {:?}", start.elapsed());
+
+        // the sleep it started went down with it
+        let pid = std::fs::read_to_string(&pid_file).unwrap().trim().parse().unwrap();
+        assert!(is_gone(pid), "sleep {} outlived the check", pid);
+    }
+
+    #[test]
+    fn parses_http_urls() {
+        assert_eq!(parse_http_url("http://10.0.0.5:9000/health").unwrap(), ("10.0.0.5".to_string(), 9000, "/health".to_string()));
+        assert_eq!(parse_http_url("http://server").unwrap(), ("server".to_string(), 80, "/".to_string()));
+        assert!(parse_http_url("https://server/").is_err());
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use async_trait::async_trait;
+use failure::{Error, ResultExt};
+
+use crate::backend::{Backend, KeyPair, Resource};
+use crate::credentials::Credentials;
+use crate::ec2::{Ec2Api, Ec2Backend};
+use crate::ledger::Ledger;
+use crate::{Machine, MachineSetup};
+
+type Connect<C> = Box<dyn Fn(&str) -> Result<Ec2Backend<C>, Error> + Send + Sync>;
+
+/*
+ * MultiRegionBackend provisions every machine set in its own region (MachineSetup::region, or the default region),
+ * through one Ec2Backend per region, each with its own key pair and security group.
+ * A single locally generated key (or the one of KeyPair::Local) is imported as the key pair of every region,
+ * so one private key logs in everywhere; a KeyPair::Existing must exist under its name in every region.
+ * Once all regions are provisioned, every security group lets in the public ips of the machines in the other
+ * regions, since private addresses do not reach across regions.
+ * Teardown tears down every region that was provisioned, and reports what any of them left behind.
+ * The ledger records every resource with its region, so burst::reap finds them in all regions.
+ */
+pub struct MultiRegionBackend<C: Ec2Api = rusoto_ec2::Ec2Client> {
+    default_region: String,
+    connect: Connect<C>,
+    regions: Vec<(String, Ec2Backend<C>)>,
+    // deleted together with the key in it when the backend is dropped
+    _key_dir: tempfile::TempDir,
+    generated_key: PathBuf,
+    key_pair: KeyPair,
+    ledger: Option<PathBuf>,
+    tags: Vec<(String, String)>,
+    max_duration: Option<Duration>,
+    ssh_user: String,
+}
+
+impl MultiRegionBackend {
+    /*
+     * Creates a backend talking to each region with credentials from the standard chain.
+     */
+    pub fn new(default_region: &str) -> Result<Self, Error> {
+        Self::with_credentials(default_region, Credentials::default_chain()?)
+    }
+
+    /*
+     * Creates a backend talking to each region with the given credentials.
+     */
+    pub fn with_credentials(default_region: &str, credentials: Credentials) -> Result<Self, Error> {
+        Self::with_connector(default_region, move |region| Ec2Backend::with_credentials(region, credentials.clone()))
+    }
+}
+
+impl<C: Ec2Api> MultiRegionBackend<C> {
+    /*
+     * Creates a backend that gets the Ec2Backend of a region from `connect`, e.g. one on top of a fake::FakeEc2.
+     */
+    pub fn with_connector<F>(default_region: &str, connect: F) -> Result<Self, Error>
+    where F: Fn(&str) -> Result<Ec2Backend<C>, Error> + Send + Sync + 'static
+    {
+        let key_dir = tempfile::Builder::new()
+            .prefix("burst_key_")
+            .tempdir()
+            .context("failed to create directory for the key pair")?;
+        Ok(MultiRegionBackend {
+            default_region: default_region.to_string(),
+            connect: Box::new(connect),
+            regions: Vec::new(),
+            generated_key: key_dir.path().join("id_ed25519"),
+            _key_dir: key_dir,
+            key_pair: KeyPair::Generated,
+            ledger: None,
+            tags: Vec::new(),
+            max_duration: None,
+            ssh_user: "ec2-user".to_string(),
+        })
+    }
+
+    /*
+     * Sets the user ssh sessions log in as first, see Ec2Backend::set_ssh_user.
+     */
+    pub fn set_ssh_user(&mut self, user: &str) {
+        self.ssh_user = user.to_string();
+    }
+
+    /*
+     * Sets up the Ec2Backend of `region`, with the run's key, ledger, tags and max duration.
+     */
+    fn regional_backend(&self, region: &str) -> Result<Ec2Backend<C>, Error> {
+        let mut backend = (self.connect)(region).context(format!("failed to connect to {}", region))?;
+        backend.set_key_pair(match self.key_pair {
+            KeyPair::Generated => KeyPair::Local(self.generated_key.clone()),
+            ref key_pair => key_pair.clone(),
+        })?;
+        if let Some(ref path) = self.ledger {
+            backend.set_ledger(Ledger::open(path)?);
+        }
+        backend.set_tags(&self.tags);
+        backend.set_ssh_user(&self.ssh_user);
+        if let Some(max_duration) = self.max_duration {
+            backend.set_max_duration(max_duration);
+        }
+        Ok(backend)
+    }
+}
+
+#[async_trait]
+impl<C: Ec2Api> Backend for MultiRegionBackend<C> {
+    async fn provision(
+        &mut self,
+        log: &slog::Logger,
+        sets: &HashMap<String, (MachineSetup, u32)>,
+    ) -> Result<HashMap<String, Vec<Machine>>, Error> {
+        let mut by_region: Vec<(String, Vec<&str>)> = Vec::new();
+        for (name, (setup, _)) in sets {
+            let region = setup.region().unwrap_or(&self.default_region);
+            match by_region.iter_mut().find(|(r, _)| r == region) {
+                Some((_, names)) => names.push(name),
+                None => by_region.push((region.to_string(), vec![name])),
+            }
+        }
+
+        for (region, _) in &by_region {
+            let backend = self.regional_backend(region)?;
+            self.regions.push((region.clone(), backend));
+        }
+
+        info!(log, "provisioning in several regions"; "regions" => by_region.len());
+        // the first region to fail stops the others, rather than let them wait on spot requests for nothing;
+        // what they created so far is recorded on their backends, and torn down with them
+        let regional = futures::future::try_join_all(self.regions.iter_mut().zip(&by_region).map(
+            |((region, backend), (_, names))| {
+                let log = log.new(o!("region" => region.clone()));
+                async move {
+                    backend.provision_sets(&log, sets, names).await
+                        .context(format!("failed to provision in {}", region))
+                        .map_err(Error::from)
+                }
+            },
+        )).await?;
+
+        debug!(log, "letting the regions reach each other");
+        for (i, (region, backend)) in self.regions.iter_mut().enumerate() {
+            let ips: Vec<String> = regional.iter()
+                .enumerate()
+                .filter(|&(j, _)| j != i)
+                .flat_map(|(_, machines)| machines.values().flatten().map(|m| m.public_ip.clone()))
+                .collect();
+            backend.allow_ingress(log, &ips).await.context(format!("failed to open up {}", region))?;
+        }
+        Ok(regional.into_iter().flatten().collect())
+    }
+
+    fn private_key(&self) -> Option<&Path> {
+        match self.key_pair {
+            KeyPair::Generated => Some(&self.generated_key),
+            KeyPair::Local(ref path) => Some(path),
+            KeyPair::Existing { ref private_key, .. } => private_key.as_deref(),
+        }
+    }
+
+    fn ssh_user(&self) -> &str {
+        &self.ssh_user
+    }
+
+    async fn teardown(&mut self, log: &slog::Logger) -> Vec<Resource> {
+        let leftovers = futures::future::join_all(self.regions.iter_mut().map(|(region, backend)| {
+            let log = log.new(o!("region" => region.clone()));
+            async move { backend.teardown(&log).await }
+        })).await;
+        leftovers.into_iter().flatten().collect()
+    }
+
+    fn set_ledger(&mut self, ledger: Ledger) {
+        // every region appends to the ledger through a handle of its own
+        self.ledger = Some(ledger.path().to_path_buf());
+    }
+
+    fn set_tags(&mut self, tags: &[(String, String)]) {
+        self.tags = tags.to_vec();
+    }
+
+    fn set_max_duration(&mut self, max_duration: Duration) {
+        self.max_duration = Some(max_duration);
+    }
+
+    fn set_key_pair(&mut self, key_pair: KeyPair) -> Result<(), Error> {
+        self.key_pair = key_pair;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::FakeEc2;
+
+    #[tokio::test]
+    async fn spans_regions_and_tears_all_of_them_down() {
+        let us = FakeEc2::new("us-east-1");
+        let eu = FakeEc2::new("eu-west-1");
+        let fakes: HashMap<String, FakeEc2> = vec![
+            ("us-east-1".to_string(), us.clone()),
+            ("eu-west-1".to_string(), eu.clone()),
+        ].into_iter().collect();
+        let mut backend = MultiRegionBackend::with_connector("us-east-1", move |region| {
+            let mut backend = Ec2Backend::with_client(region, fakes[region].clone())?;
+            backend.set_backoff(crate::poll::TEST_BACKOFF);
+            Ok(backend)
+        }).unwrap();
+        let log = slog::Logger::root(slog::Discard, o!());
+        let mut sets = HashMap::new();
+        sets.insert("replica-us".to_string(), (MachineSetup::new("t3.small", "ami-e18aa89b", |_| Ok(())), 2));
+        let mut eu_setup = MachineSetup::new("t3.small", "ami-0bdf93799014acdc4", |_| Ok(()));
+        eu_setup.set_region("eu-west-1");
+        sets.insert("replica-eu".to_string(), (eu_setup, 1));
+
+        let machines = backend.provision(&log, &sets).await.unwrap();
+        assert_eq!(machines["replica-us"].len(), 2);
+        assert_eq!(machines["replica-eu"].len(), 1);
+        assert_eq!(machines["replica-eu"][0].availability_zone, "eu-west-1a");
+        assert_eq!(us.instances_in_state("running").len(), 2);
+        assert_eq!(eu.instances_in_state("running").len(), 1);
+
+        let public_key = std::fs::read_to_string(backend.private_key().unwrap().with_extension("pub")).unwrap();
+        for fake in &[&us, &eu] {
+            assert_eq!(fake.public_key(&fake.key_pairs()[0]).as_deref(), Some(public_key.trim()));
+        }
+        let eu_ip = format!("{}/32", machines["replica-eu"][0].public_ip);
+        assert!(us.ingress(&us.security_groups()[0]).contains(&eu_ip));
+        for machine in &machines["replica-us"] {
+            assert!(eu.ingress(&eu.security_groups()[0]).contains(&format!("{}/32", machine.public_ip)));
+        }
+
+        assert!(backend.teardown(&log).await.is_empty());
+        for fake in &[&us, &eu] {
+            assert!(fake.instances_in_state("running").is_empty());
+            assert!(fake.security_groups().is_empty());
+            assert!(fake.key_pairs().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn a_failing_region_stops_the_others() {
+        let us = FakeEc2::new("us-east-1");
+        let eu = FakeEc2::new("eu-west-1");
+        us.set_capacity("t3.small", 0);
+        eu.set_spot_price("t3.small", 0.02);
+        let fakes: HashMap<String, FakeEc2> = vec![
+            ("us-east-1".to_string(), us.clone()),
+            ("eu-west-1".to_string(), eu.clone()),
+        ].into_iter().collect();
+        let mut backend = MultiRegionBackend::with_connector("us-east-1", move |region| {
+            let mut backend = Ec2Backend::with_client(region, fakes[region].clone())?;
+            backend.set_backoff(crate::poll::TEST_BACKOFF);
+            Ok(backend)
+        }).unwrap();
+        let log = slog::Logger::root(slog::Discard, o!());
+        let mut sets = HashMap::new();
+        sets.insert("replica-us".to_string(), (MachineSetup::new("t3.small", "ami-e18aa89b", |_| Ok(())), 1));
+        // would wait for the spot price to drop for an hour
+        let mut eu_setup = MachineSetup::new("t3.small", "ami-0bdf93799014acdc4", |_| Ok(()));
+        eu_setup.set_region("eu-west-1");
+        eu_setup.set_market(crate::Market::Spot { max_price: Some(0.01) });
+        eu_setup.set_fulfillment(1, Duration::from_secs(3600));
+        sets.insert("replica-eu".to_string(), (eu_setup, 1));
+
+        let provision = tokio::time::timeout(Duration::from_secs(5), backend.provision(&log, &sets)).await;
+        let err = match provision.expect("provisioning stopped at the first failure") {
+            Ok(_) => panic!("provisioning succeeded without capacity in us-east-1"),
+            Err(e) => e,
+        };
+        assert!(err.to_string().contains("us-east-1"), "{}", err);
+
+        assert!(backend.teardown(&log).await.is_empty());
+        for fake in &[&us, &eu] {
+            assert!(fake.spot_requests_in_state("open").is_empty());
+            assert!(fake.security_groups().is_empty());
+            assert!(fake.key_pairs().is_empty());
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use failure::{Error, ResultExt};
+use ssh2::{FileStat, OpenFlags, OpenType, Sftp};
+
+use crate::ssh::Session;
+
+// how much is read or written per sftp request, and between two progress reports
+const CHUNK: usize = 32 * 1024;
+
+/*
+ * Progress is how far along a transfer is, reported after every chunk that was copied.
+ * file: the local path of the file being copied
+ * file_done, file_size: how many bytes of that file were copied, out of how many
+ * done, total: how many bytes of the whole transfer were copied, out of how many
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Progress<'a> {
+    pub file: &'a Path,
+    pub file_done: u64,
+    pub file_size: u64,
+    pub done: u64,
+    pub total: u64,
+}
+
+/*
+ * Entry is a file or directory of a transfer, with where it goes and its permission bits.
+ */
+#[derive(Debug, PartialEq, Eq)]
+struct Entry {
+    from: PathBuf,
+    to: PathBuf,
+    mode: u32,
+    // None for directories
+    size: Option<u64>,
+}
+
+/*
+ * Everything under the local `from` (itself included), in an order where directories come before their contents.
+ */
+fn local_tree(from: &Path, to: &Path) -> Result<Vec<Entry>, Error> {
+    let meta = fs::metadata(from).context(format!("failed to read {}", from.display()))?;
+    let mode = meta.permissions().mode() & 0o7777;
+    if !meta.is_dir() {
+        return Ok(vec![Entry { from: from.to_path_buf(), to: to.to_path_buf(), mode, size: Some(meta.len()) }]);
+    }
+    let mut entries = vec![Entry { from: from.to_path_buf(), to: to.to_path_buf(), mode, size: None }];
+    let mut children: Vec<_> = fs::read_dir(from)
+        .context(format!("failed to list {}", from.display()))?
+        .collect::<Result<_, _>>()
+        .context(format!("failed to list {}", from.display()))?;
+    children.sort_by_key(|child| child.file_name());
+    for child in children {
+        entries.extend(local_tree(&child.path(), &to.join(child.file_name()))?);
+    }
+    Ok(entries)
+}
+
+/*
+ * Everything under the remote `from` (itself included), in an order where directories come before their contents.
+ * Symbolic links and other special files are skipped.
+ */
+fn remote_tree(sftp: &Sftp, from: &Path, to: &Path, stat: FileStat) -> Result<Vec<Entry>, Error> {
+    let mode = stat.perm.unwrap_or(0o644) & 0o7777;
+    if stat.is_file() {
+        return Ok(vec![Entry { from: from.to_path_buf(), to: to.to_path_buf(), mode, size: stat.size }]);
+    }
+    if !stat.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut entries = vec![Entry { from: from.to_path_buf(), to: to.to_path_buf(), mode, size: None }];
+    let mut children = sftp.readdir(from).context(format!("failed to list remote {}", from.display()))?;
+    children.sort_by(|a, b| a.0.cmp(&b.0));
+    for (child, stat) in children {
+        let name = child.file_name().ok_or_else(|| failure::format_err!("remote {} has no name", child.display()))?;
+        entries.extend(remote_tree(sftp, &child, &to.join(name), stat)?);
+    }
+    Ok(entries)
+}
+
+/*
+ * Copies `from` to `to` chunk by chunk, reporting progress on `file` after every chunk.
+ */
+fn copy<R: Read, W: Write>(
+    from: &mut R,
+    to: &mut W,
+    file: &Path,
+    file_size: u64,
+    done: &mut u64,
+    total: u64,
+    progress: &mut dyn FnMut(&Progress),
+) -> std::io::Result<()> {
+    let mut buf = vec![0u8; CHUNK];
+    let mut file_done = 0;
+    loop {
+        let n = from.read(&mut buf)?;
+        if n == 0 {
+            return to.flush();
+        }
+        to.write_all(&buf[..n])?;
+        file_done += n as u64;
+        *done += n as u64;
+        progress(&Progress { file, file_done, file_size, done: *done, total });
+    }
+}
+
+/*
+ * Copying files to and from the machine over SFTP, keeping their permission bits.
+ * Directories are copied recursively by upload_dir and download_dir, and existing files are overwritten.
+ * Every copied file is logged with the session's logger; the _with variants also report the progress
+ * of every chunk to a callback.
+ */
+impl Session {
+    pub fn upload(&mut self, local: &Path, remote: &Path) -> Result<(), Error> {
+        if fs::metadata(local).context(format!("failed to read {}", local.display()))?.is_dir() {
+            return Err(failure::format_err!("{} is a directory, see upload_dir", local.display()));
+        }
+        self.upload_with(local, remote, |_| {})
+    }
+
+    pub fn upload_dir(&mut self, local: &Path, remote: &Path) -> Result<(), Error> {
+        if !fs::metadata(local).context(format!("failed to read {}", local.display()))?.is_dir() {
+            return Err(failure::format_err!("{} is not a directory, see upload", local.display()));
+        }
+        self.upload_with(local, remote, |_| {})
+    }
+
+    pub fn download(&mut self, remote: &Path, local: &Path) -> Result<(), Error> {
+        let stat = self.sftp()
+            .context("failed to start sftp session")?
+            .stat(remote)
+            .context(format!("failed to read remote {}", remote.display()))?;
+        if stat.is_dir() {
+            return Err(failure::format_err!("remote {} is a directory, see download_dir", remote.display()));
+        }
+        self.download_with(remote, local, |_| {})
+    }
+
+    pub fn download_dir(&mut self, remote: &Path, local: &Path) -> Result<(), Error> {
+        let stat = self.sftp()
+            .context("failed to start sftp session")?
+            .stat(remote)
+            .context(format!("failed to read remote {}", remote.display()))?;
+        if !stat.is_dir() {
+            return Err(failure::format_err!("remote {} is not a directory, see download", remote.display()));
+        }
+        self.download_with(remote, local, |_| {})
+    }
+
+    /*
+     * Uploads the local file or directory `local` to `remote`, calling `progress` after every chunk.
+     */
+    pub fn upload_with<F>(&mut self, local: &Path, remote: &Path, mut progress: F) -> Result<(), Error>
+    where F: FnMut(&Progress)
+    {
+        let entries = local_tree(local, remote)?;
+        let total = entries.iter().filter_map(|e| e.size).sum();
+        let sftp = self.sftp().context("failed to start sftp session")?;
+        let mut done = 0;
+        for entry in entries {
+            let mode = FileStat { perm: Some(entry.mode), size: None, uid: None, gid: None, atime: None, mtime: None };
+            match entry.size {
+                None => {
+                    if sftp.stat(&entry.to).map(|stat| !stat.is_dir()).unwrap_or(true) {
+                        sftp.mkdir(&entry.to, entry.mode as i32)
+                            .context(format!("failed to create remote directory {}", entry.to.display()))?;
+                    }
+                }
+                Some(size) => {
+                    let mut from = fs::File::open(&entry.from).context(format!("failed to open {}", entry.from.display()))?;
+                    let mut to = sftp
+                        .open_mode(
+                            &entry.to,
+                            OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+                            entry.mode as i32,
+                            OpenType::File,
+                        )
+                        .context(format!("failed to create remote file {}", entry.to.display()))?;
+                    copy(&mut from, &mut to, &entry.from, size, &mut done, total, &mut progress)
+                        .context(format!("failed to upload {} to {}", entry.from.display(), entry.to.display()))?;
+                    debug!(self.log(), "uploaded {}", entry.from.display(); "to" => entry.to.display().to_string(), "bytes" => size);
+                }
+            }
+            // the mode given on creation is subject to the remote umask, and ignored for existing files
+            sftp.setstat(&entry.to, mode)
+                .context(format!("failed to set permissions of remote {}", entry.to.display()))?;
+        }
+        Ok(())
+    }
+
+    /*
+     * Downloads the remote file or directory `remote` to `local`, calling `progress` after every chunk.
+     */
+    pub fn download_with<F>(&mut self, remote: &Path, local: &Path, mut progress: F) -> Result<(), Error>
+    where F: FnMut(&Progress)
+    {
+        let sftp = self.sftp().context("failed to start sftp session")?;
+        let stat = sftp.stat(remote).context(format!("failed to read remote {}", remote.display()))?;
+        let entries = remote_tree(&sftp, remote, local, stat)?;
+        let total = entries.iter().filter_map(|e| e.size).sum();
+        let mut done = 0;
+        for entry in entries {
+            match entry.size {
+                None => {
+                    fs::create_dir_all(&entry.to).context(format!("failed to create {}", entry.to.display()))?;
+                }
+                Some(size) => {
+                    let mut from = sftp.open(&entry.from)
+                        .context(format!("failed to open remote file {}", entry.from.display()))?;
+                    let mut to = fs::File::create(&entry.to).context(format!("failed to create {}", entry.to.display()))?;
+                    copy(&mut from, &mut to, &entry.to, size, &mut done, total, &mut progress)
+                        .context(format!("failed to download {} to {}", entry.from.display(), entry.to.display()))?;
+                    debug!(self.log(), "downloaded {}", entry.from.display(); "to" => entry.to.display().to_string(), "bytes" => size);
+                }
+            }
+            fs::set_permissions(&entry.to, fs::Permissions::from_mode(entry.mode))
+                .context(format!("failed to set permissions of {}", entry.to.display()))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn walks_local_directories_before_their_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("deploy");
+        fs::create_dir_all(root.join("bin")).unwrap();
+        fs::write(root.join("bin/server"), b"\x7fELF").unwrap();
+        fs::set_permissions(root.join("bin/server"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(root.join("config.toml"), b"port = 9000\n").unwrap();
+        fs::set_permissions(root.join("config.toml"), fs::Permissions::from_mode(0o600)).unwrap();
+
+        let entries = local_tree(&root, Path::new("/opt/deploy")).unwrap();
+        let remote: Vec<(&Path, Option<u64>)> = entries.iter().map(|e| (e.to.as_path(), e.size)).collect();
+        assert_eq!(remote, [
+            (Path::new("/opt/deploy"), None),
+            (Path::new("/opt/deploy/bin"), None),
+            (Path::new("/opt/deploy/bin/server"), Some(4)),
+            (Path::new("/opt/deploy/config.toml"), Some(12)),
+        ]);
+        assert_eq!(entries[2].mode, 0o755);
+        assert_eq!(entries[3].mode, 0o600);
+    }
+
+    #[test]
+    fn reports_progress_of_every_chunk() {
+        let data = vec![7u8; CHUNK * 2 + 10];
+        let mut copied = Vec::new();
+        let mut done = 100;
+        let mut reports = Vec::new();
+        copy(&mut &data[..], &mut copied, Path::new("big"), data.len() as u64, &mut done, 200 + data.len() as u64, &mut |p| {
+            reports.push((p.file_done, p.done));
+        }).unwrap();
+        assert_eq!(copied, data);
+        let chunk = CHUNK as u64;
+        assert_eq!(reports, [(chunk, 100 + chunk), (2 * chunk, 100 + 2 * chunk), (2 * chunk + 10, 110 + 2 * chunk)]);
+        assert_eq!(done, 110 + 2 * chunk);
+    }
+}
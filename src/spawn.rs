@@ -0,0 +1,210 @@
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use failure::{Error, ResultExt};
+
+use crate::ssh::{quote, CommandFailed, CommandTimeout, Session};
+
+/*
+ * Process is a command started in the background on a machine with Session::spawn.
+ * It keeps running when the session that started it is closed; its stdout, stderr and exit status
+ * are kept in a directory of its own on the machine until the run is over.
+ * The methods take the session to the machine, or a clone of it.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Process {
+    pub cmd: String,
+    pub pid: u32,
+    dir: String,
+}
+
+/*
+ * Logs is what a spawned process has written so far.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Logs {
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/*
+ * What a process looks like from the outside: still running, or exited with a status (-1 if killed by a signal).
+ */
+fn state(out: &str) -> Result<Option<i32>, Error> {
+    match out.trim() {
+        "running" => Ok(None),
+        "killed" => Ok(Some(-1)),
+        status => Ok(Some(status.parse::<i32>().context(format!("unexpected process state '{}'", status))?)),
+    }
+}
+
+impl Process {
+    /*
+     * Starts `cmd` on the machine of `sess` in its own process group, detached from the session.
+     */
+    pub(crate) fn start(sess: &mut Session, cmd: &str) -> Result<Self, Error> {
+        let out = sess.exec_checked(&format!(
+            "dir=$(mktemp -d /tmp/burst-spawn-XXXXXX) && \
+             setsid sh -c '(eval \"$1\") >\"$2/stdout\" 2>\"$2/stderr\" </dev/null; echo $? >\"$2/status\"' burst-spawn {} \"$dir\" \
+             </dev/null >/dev/null 2>&1 & echo $! \"$dir\"",
+            quote(cmd)
+        )).context(format!("failed to spawn '{}'", cmd))?;
+        let mut out = out.stdout.split_whitespace();
+        match (out.next().and_then(|pid| pid.parse().ok()), out.next()) {
+            (Some(pid), Some(dir)) => Ok(Process { cmd: cmd.to_string(), pid, dir: dir.to_string() }),
+            _ => Err(failure::format_err!("failed to spawn '{}'", cmd)),
+        }
+    }
+
+    fn state(&self, sess: &mut Session) -> Result<Option<i32>, Error> {
+        let out = sess.exec_checked(&format!(
+            "if kill -0 {pid} 2>/dev/null; then echo running; elif [ -s {dir}/status ]; then cat {dir}/status; else echo killed; fi",
+            pid = self.pid,
+            dir = quote(&self.dir)
+        )).context(format!("failed to check on '{}'", self.cmd))?;
+        state(&out.stdout)
+    }
+
+    pub fn is_running(&self, sess: &mut Session) -> Result<bool, Error> {
+        Ok(self.state(sess)?.is_none())
+    }
+
+    /*
+     * Waits for the process to exit and returns its exit status, -1 if it was killed by a signal.
+     * Gives up with CommandTimeout after the timeout of the session, if it has one.
+     */
+    pub fn wait(&self, sess: &mut Session) -> Result<i32, Error> {
+        let start = Instant::now();
+        let mut delay = Duration::from_millis(50);
+        loop {
+            if let Some(status) = self.state(sess)? {
+                return Ok(status);
+            }
+            if let Some(timeout) = sess.timeout().filter(|&timeout| start.elapsed() >= timeout) {
+                return Err(CommandTimeout { cmd: self.cmd.clone(), timeout }.into());
+            }
+            thread::sleep(delay);
+            delay = (delay * 2).min(Duration::from_secs(1));
+        }
+    }
+
+    /*
+     * Sends `signal` (e.g. "TERM", "INT" or "KILL") to the process and every process it started.
+     * Processes that already exited are left alone.
+     */
+    pub fn kill(&self, sess: &mut Session, signal: &str) -> Result<(), Error> {
+        sess.exec_checked(&format!(
+            "kill -{sig} -- -{pid} 2>/dev/null || ! kill -0 {pid} 2>/dev/null",
+            sig = quote(signal),
+            pid = self.pid
+        )).context(format!("failed to signal '{}'", self.cmd))?;
+        Ok(())
+    }
+
+    pub fn logs(&self, sess: &mut Session) -> Result<Logs, Error> {
+        self.read_logs(sess, None)
+    }
+
+    /*
+     * Reads the logs like logs, but fails with CommandTimeout if that takes longer than `timeout`.
+     */
+    pub(crate) fn logs_timeout(&self, sess: &mut Session, timeout: Duration) -> Result<Logs, Error> {
+        self.read_logs(sess, Some(timeout))
+    }
+
+    fn read_logs(&self, sess: &mut Session, timeout: Option<Duration>) -> Result<Logs, Error> {
+        let start = Instant::now();
+        let mut read = |name: &str| -> Result<String, Error> {
+            let cmd = format!("cat {}/{}", quote(&self.dir), name);
+            let out = match timeout {
+                Some(timeout) => sess.exec_timeout(&cmd, timeout.saturating_sub(start.elapsed())),
+                None => sess.exec(&cmd),
+            };
+            let out = out
+                .and_then(|out| if out.success() { Ok(out) } else { Err(CommandFailed { cmd, output: out }.into()) })
+                .context(format!("failed to read the {} of '{}'", name, self.cmd))?;
+            Ok(out.stdout)
+        };
+        Ok(Logs { stdout: read("stdout")?, stderr: read("stderr")? })
+    }
+}
+
+// how long processes get to exit after TERM before they are killed
+const TERM_GRACE: Duration = Duration::from_secs(5);
+
+/*
+ * Stops every process spawned through `sess` (or its clones) that is still running, first with TERM and
+ * then with KILL, and collects their logs into `dir` as {prefix}-{pid}.stdout and .stderr if given,
+ * or into the session's logger otherwise. The processes' directories on the machine are deleted.
+ * A process that cannot be stopped or collected is logged, and the others are collected all the same.
+ */
+pub(crate) fn collect(sess: &mut Session, dir: Option<&Path>, prefix: &str) {
+    for process in sess.take_spawned() {
+        let log = sess.log().new(o!("cmd" => process.cmd.clone(), "pid" => process.pid));
+        if let Err(e) = collect_one(sess, &log, &process, dir, prefix) {
+            warn!(log, "failed to collect spawned process: {}", e);
+        }
+    }
+}
+
+fn collect_one(sess: &mut Session, log: &slog::Logger, process: &Process, dir: Option<&Path>, prefix: &str) -> Result<(), Error> {
+    if process.is_running(sess)? {
+        debug!(log, "stopping spawned process");
+        process.kill(sess, "TERM")?;
+        let start = Instant::now();
+        while process.is_running(sess)? {
+            if start.elapsed() >= TERM_GRACE {
+                process.kill(sess, "KILL")?;
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+    let logs = process.logs(sess)?;
+    match dir {
+        Some(dir) => {
+            fs::create_dir_all(dir).context(format!("failed to create {}", dir.display()))?;
+            for (name, content) in [("stdout", &logs.stdout), ("stderr", &logs.stderr)] {
+                let path = dir.join(format!("{}-{}.{}", prefix, process.pid, name));
+                fs::write(&path, content).context(format!("failed to save {}", path.display()))?;
+            }
+        }
+        None => {
+            for line in logs.stdout.lines() {
+                info!(log, "{}", line; "stream" => "stdout");
+            }
+            for line in logs.stderr.lines() {
+                info!(log, "{}", line; "stream" => "stderr");
+            }
+        }
+    }
+    sess.exec_checked(&format!("rm -rf {}", quote(&process.dir)))?;
+    Ok(())
+}
+
+/*
+ * Whether the local process `pid` is gone, or a zombie that nothing has reaped yet, for tests of what
+ * outlives what; the machines of the local backend share this one's processes.
+ */
+#[cfg(test)]
+pub(crate) fn is_gone(pid: u32) -> bool {
+    let state = fs::read_to_string(format!("/proc/{}/stat", pid))
+        .ok()
+        .and_then(|stat| stat.rsplit_once(") ").and_then(|(_, rest)| rest.chars().next()));
+    matches!(state, None | Some('Z'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_process_states() {
+        assert_eq!(state("running\n").unwrap(), None);
+        assert_eq!(state("0\n").unwrap(), Some(0));
+        assert_eq!(state("137\n").unwrap(), Some(137));
+        assert_eq!(state("killed\n").unwrap(), Some(-1));
+        assert!(state("bash: kill: not found").is_err());
+    }
+}
@@ -0,0 +1,240 @@
+use std::fmt;
+use failure::{Error, Fail};
+
+/*
+ * SpotRequestState is the state of a spot request as AWS reports it.
+ * A request is open until it either launched its instance (active), or ended without one
+ * (closed, cancelled or failed). An active request that is cancelled keeps its instance running.
+ * A request is disabled when its instance was stopped, which the instances of a run never are.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpotRequestState {
+    Open,
+    Active,
+    Closed,
+    Cancelled,
+    Failed,
+    Disabled,
+}
+
+impl SpotRequestState {
+    fn from_aws(state: &str) -> Option<Self> {
+        Some(match state {
+            "open" => SpotRequestState::Open,
+            "active" => SpotRequestState::Active,
+            "closed" => SpotRequestState::Closed,
+            "cancelled" => SpotRequestState::Cancelled,
+            "failed" => SpotRequestState::Failed,
+            "disabled" => SpotRequestState::Disabled,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for SpotRequestState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            SpotRequestState::Open => "open",
+            SpotRequestState::Active => "active",
+            SpotRequestState::Closed => "closed",
+            SpotRequestState::Cancelled => "cancelled",
+            SpotRequestState::Failed => "failed",
+            SpotRequestState::Disabled => "disabled",
+        })
+    }
+}
+
+/*
+ * Status codes of open requests that will not be fulfilled unless something changes, like the spot price
+ * dropping or capacity freeing up. See
+ * https://docs.aws.amazon.com/AWSEC2/latest/UserGuide/spot-request-status.html
+ */
+const STUCK: &[&str] = &[
+    "capacity-not-available",
+    "capacity-oversubscribed",
+    "price-too-low",
+    "constraint-not-fulfillable",
+    "az-group-constraint",
+    "placement-group-constraint",
+    "launch-group-constraint",
+];
+
+/*
+ * Status codes that mean the availability zone has no room for the request, so another zone may.
+ */
+const OUT_OF_CAPACITY: &[&str] = &[
+    "capacity-not-available",
+    "capacity-oversubscribed",
+];
+
+/*
+ * SpotRequestStatus is where one spot request of a machine set stands: its state, together with the
+ * AWS status code and message saying why, e.g. open / price-too-low, and the instance once it launched one.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpotRequestStatus {
+    pub id: String,
+    pub set: String,
+    pub state: SpotRequestState,
+    pub code: String,
+    pub message: String,
+    pub instance_id: Option<String>,
+    pub instance_type: Option<String>,
+    pub zone: Option<String>,
+}
+
+/*
+ * The id of a spot request as AWS described it, which it should always come with.
+ */
+pub(crate) fn request_id(sir: &rusoto_ec2::SpotInstanceRequest) -> Result<&str, Error> {
+    sir.spot_instance_request_id.as_deref()
+        .ok_or_else(|| failure::format_err!("aws described a spot request without a spot request id"))
+}
+
+impl SpotRequestStatus {
+    /*
+     * Fails for requests without an id and states this version does not know about, rather than guessing.
+     */
+    pub(crate) fn from_aws(sir: rusoto_ec2::SpotInstanceRequest, set: &str) -> Result<Self, Error> {
+        let id = request_id(&sir)?.to_string();
+        let status = sir.status.unwrap_or_default();
+        let launch = sir.launch_specification.unwrap_or_default();
+        let state = match sir.state.as_deref().and_then(SpotRequestState::from_aws) {
+            Some(state) => state,
+            None => {
+                return Err(failure::format_err!(
+                    "spot request {} is in unknown state {}", id, sir.state.as_deref().unwrap_or("(none)")
+                ));
+            }
+        };
+        Ok(SpotRequestStatus {
+            id,
+            set: set.to_string(),
+            state,
+            code: status.code.unwrap_or_default(),
+            message: status.message.unwrap_or_default(),
+            instance_id: sir.instance_id,
+            instance_type: launch.instance_type,
+            zone: sir.launched_availability_zone.or_else(|| launch.placement.and_then(|p| p.availability_zone)),
+        })
+    }
+
+    /*
+     * Whether the request launched an instance, which keeps running even if the request was cancelled since.
+     */
+    pub fn is_fulfilled(&self) -> bool {
+        self.instance_id.is_some()
+            && (self.state == SpotRequestState::Active || self.state == SpotRequestState::Cancelled)
+    }
+
+    /*
+     * Whether the request may still be fulfilled without anything changing.
+     */
+    pub fn is_pending(&self) -> bool {
+        match self.state {
+            SpotRequestState::Open => !self.is_stuck(),
+            SpotRequestState::Active => self.instance_id.is_none(),
+            _ => false,
+        }
+    }
+
+    /*
+     * Whether the request is open but waiting on something that may never happen, like a lower spot price.
+     */
+    pub fn is_stuck(&self) -> bool {
+        self.state == SpotRequestState::Open && STUCK.contains(&self.code.as_str())
+    }
+
+    /*
+     * Whether the request failed for lack of capacity in its availability zone.
+     */
+    pub fn is_out_of_capacity(&self) -> bool {
+        !self.is_fulfilled() && OUT_OF_CAPACITY.contains(&self.code.as_str())
+    }
+}
+
+/*
+ * SpotRequestFailed is the error provisioning fails with as soon as a spot request ended up, or got stuck,
+ * in a state in which it will not launch an instance.
+ * zones_tried lists the availability zones the request was tried in, when retrying in other zones is enabled,
+ * and instance_types_tried the instance types, when the machine set accepts more than one.
+ */
+#[derive(Debug)]
+pub struct SpotRequestFailed {
+    pub status: SpotRequestStatus,
+    pub zones_tried: Vec<String>,
+    pub instance_types_tried: Vec<String>,
+}
+
+impl fmt::Display for SpotRequestFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "spot request {} for {} machine ended up {} ({}: {})",
+            self.status.id, self.status.set, self.status.state, self.status.code, self.status.message
+        )?;
+        if !self.zones_tried.is_empty() {
+            write!(f, " after trying {}", self.zones_tried.join(", "))?;
+        }
+        if !self.instance_types_tried.is_empty() {
+            write!(f, " with instance types {}", self.instance_types_tried.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl Fail for SpotRequestFailed {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(state: &str, code: &str, instance_id: Option<&str>) -> SpotRequestStatus {
+        SpotRequestStatus::from_aws(rusoto_ec2::SpotInstanceRequest {
+            spot_instance_request_id: Some("sir-1".to_string()),
+            state: Some(state.to_string()),
+            status: Some(rusoto_ec2::SpotInstanceStatus {
+                code: Some(code.to_string()),
+                ..Default::default()
+            }),
+            instance_id: instance_id.map(str::to_string),
+            ..Default::default()
+        }, "client").unwrap()
+    }
+
+    #[test]
+    fn classifies_spot_requests() {
+        let pending = status("open", "pending-evaluation", None);
+        assert!(pending.is_pending() && !pending.is_stuck() && !pending.is_fulfilled());
+
+        let too_cheap = status("open", "price-too-low", None);
+        assert!(!too_cheap.is_pending() && too_cheap.is_stuck() && !too_cheap.is_out_of_capacity());
+
+        let full = status("closed", "capacity-not-available", None);
+        assert!(!full.is_pending() && !full.is_stuck() && full.is_out_of_capacity());
+
+        let launching = status("active", "fulfilled", None);
+        assert!(launching.is_pending());
+
+        let cancelled = status("cancelled", "request-canceled-and-instance-running", Some("i-1"));
+        assert!(cancelled.is_fulfilled() && !cancelled.is_pending());
+
+        assert!(!status("failed", "bad-parameters", None).is_pending());
+
+        let stopped = status("disabled", "marked-for-stop", Some("i-1"));
+        assert!(!stopped.is_pending() && !stopped.is_fulfilled());
+
+        let unknown = SpotRequestStatus::from_aws(rusoto_ec2::SpotInstanceRequest {
+            spot_instance_request_id: Some("sir-2".to_string()),
+            state: Some("hibernated".to_string()),
+            ..Default::default()
+        }, "client");
+        assert_eq!(unknown.unwrap_err().to_string(), "spot request sir-2 is in unknown state hibernated");
+
+        let anonymous = SpotRequestStatus::from_aws(rusoto_ec2::SpotInstanceRequest {
+            state: Some("open".to_string()),
+            ..Default::default()
+        }, "client");
+        assert!(anonymous.is_err());
+    }
+}
@@ -1,17 +1,159 @@
-use ssh2;
 use std::{net::{ TcpStream, SocketAddr}, thread};
-use failure::{Error};
+use failure::{Error, Fail};
+use std::fmt;
 use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::time::{Instant, Duration};
 use failure::ResultExt;
 
 pub struct Session {
     ssh: ssh2::Session,
-    _stream: TcpStream
+    _stream: TcpStream,
+    log: slog::Logger,
+    timeout: Option<Duration>,
+    timeout_signal: Option<String>,
+    // shared with the clones of the session
+    spawned: Arc<Mutex<Vec<Process>>>,
 }
 
+/*
+ * Stream is which of its outputs a line written by a command came from.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    fn name(self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
+
+/*
+ * LineBuffer collects what a command writes to one of its outputs, and hands out every complete line.
+ */
+#[derive(Default)]
+struct LineBuffer {
+    all: Vec<u8>,
+    // where the line that is not complete yet starts in `all`
+    start: usize,
+}
+
+impl LineBuffer {
+    fn push(&mut self, data: &[u8], mut line: impl FnMut(&str)) {
+        self.all.extend_from_slice(data);
+        while let Some(end) = self.all[self.start..].iter().position(|&b| b == b'\n') {
+            let end = self.start + end;
+            line(String::from_utf8_lossy(&self.all[self.start..end]).trim_end_matches('\r'));
+            self.start = end + 1;
+        }
+    }
+
+    /*
+     * Hands out the last line if it did not end with a newline, and returns everything that was written.
+     */
+    fn finish(self, mut line: impl FnMut(&str)) -> String {
+        if self.start < self.all.len() {
+            line(&String::from_utf8_lossy(&self.all[self.start..]));
+        }
+        String::from_utf8_lossy(&self.all).into_owned()
+    }
+}
+
+/*
+ * CommandOutput is what a command run with Session::exec left behind.
+ * status: the exit status of the command, -1 if it was killed by a signal
+ * stdout, stderr: everything the command wrote to them, lossily decoded as UTF-8
+ * duration: how long the command ran for, as seen from this end of the session
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandOutput {
+    pub status: i32,
+    pub stdout: String,
+    pub stderr: String,
+    pub duration: Duration,
+}
+
+impl CommandOutput {
+    pub fn success(&self) -> bool {
+        self.status == 0
+    }
+}
+
+/*
+ * CommandFailed is the error Session::exec_checked fails with when the command exits with a non-zero status.
+ * It carries the command's output, and shows the last lines of its stderr.
+ */
+#[derive(Debug)]
+pub struct CommandFailed {
+    pub cmd: String,
+    pub output: CommandOutput,
+}
+
+impl fmt::Display for CommandFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "command '{}' exited with status {}", self.cmd, self.output.status)?;
+        let tail = tail(&self.output.stderr, STDERR_TAIL_LINES);
+        if !tail.is_empty() {
+            write!(f, ": {}", tail)?;
+        }
+        Ok(())
+    }
+}
+
+impl Fail for CommandFailed {}
+
+/*
+ * CommandTimeout is the error a command fails with when it did not complete within the timeout
+ * of its session (Session::set_timeout) or its own (Session::exec_timeout).
+ */
+#[derive(Debug)]
+pub struct CommandTimeout {
+    pub cmd: String,
+    pub timeout: Duration,
+}
+
+impl fmt::Display for CommandTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "command '{}' timed out after {:?}", self.cmd, self.timeout)
+    }
+}
+
+impl Fail for CommandTimeout {}
+
+// how much of stderr CommandFailed shows
+const STDERR_TAIL_LINES: usize = 10;
+
+// how long signalling a timed out command may take, however little time the command itself had
+const SIGNAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/*
+ * The last `lines` non-empty lines of `s`.
+ */
+fn tail(s: &str, lines: usize) -> String {
+    let mut tail: Vec<&str> = s.lines().rev().filter(|l| !l.trim().is_empty()).take(lines).collect();
+    tail.reverse();
+    tail.join("\n")
+}
+
+/*
+ * Login users of the common AMIs (Amazon Linux, Ubuntu, Debian, CentOS), tried in this order when
+ * a machine set does not say which user to log in as.
+ */
+pub(crate) const FALLBACK_USERS: &[&str] = &["ec2-user", "ubuntu", "admin", "centos"];
+
 impl Session  {
-    pub(crate) fn connect(addr: SocketAddr, key: &Path) -> Result<Self, Error> {
+    /*
+     * Connects and authenticates as the first of `users` the key is accepted for,
+     * returning the session together with that user.
+     * Without a private key file, the keys held by the ssh-agent are offered instead.
+     */
+    pub(crate) fn connect_as(addr: SocketAddr, users: &[&str], key: Option<&Path>) -> Result<(Self, String), Error> {
         
         let start = Instant::now();
 
@@ -33,39 +175,271 @@ impl Session  {
         sess.handshake()
             .context("failed to perform ssh handshake")?;
 
-        // ssh using the private key saved in temporary file, generated programmatically
-        sess.userauth_pubkey_file("ec2-user", None, key, None)
-            .context("failed to authenticate ssh session")?;
-         
-        Ok(Session{
-            ssh: sess,
-            _stream: tcp
-        })
+        // ssh using the private key saved in temporary file, generated programmatically, or the ssh-agent
+        let mut failure = None;
+        for user in users {
+            let auth = match key {
+                Some(key) => sess.userauth_pubkey_file(user, None, key, None),
+                None => sess.userauth_agent(user),
+            };
+            match auth {
+                Ok(()) => {
+                    return Ok((Session{
+                        ssh: sess,
+                        _stream: tcp,
+                        log: slog::Logger::root(slog::Discard, o!()),
+                        timeout: None,
+                        timeout_signal: None,
+                        spawned: Arc::new(Mutex::new(Vec::new())),
+                    }, user.to_string()));
+                }
+                Err(e) => failure = Some(e),
+            }
+        }
+        match failure {
+            Some(e) => Err(e).context(format!("failed to authenticate ssh session as any of {}", users.join(", ")))?,
+            None => Err(failure::err_msg("no user to authenticate ssh session as")),
+        }
     }
 
+    /*
+     * Runs `cmd` and returns its stdout, whatever its exit status; see exec_checked for one that fails.
+     */
     pub fn cmd(&mut self, cmd: &str) -> Result<String, Error> {
-        use std::io::Read;
-        
+        Ok(self.exec(cmd)?.stdout)
+    }
+
+    /*
+     * Runs `cmd` and returns its exit status, stdout and stderr, whatever the exit status.
+     */
+    pub fn exec(&mut self, cmd: &str) -> Result<CommandOutput, Error> {
+        self.run(cmd, self.timeout, &mut |_, _| {})
+    }
+
+    /*
+     * Runs `cmd` like exec, but fails with CommandTimeout if it has not completed within `timeout`,
+     * whatever the timeout of the session.
+     */
+    pub fn exec_timeout(&mut self, cmd: &str, timeout: Duration) -> Result<CommandOutput, Error> {
+        self.run(cmd, Some(timeout), &mut |_, _| {})
+    }
+
+    /*
+     * Makes every command run through the session fail with CommandTimeout once it has run for longer
+     * than `timeout`, and every other ssh operation (e.g. an sftp transfer) fail once the machine has not
+     * responded for that long. There is no timeout by default.
+     */
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+        self.ssh.set_timeout(timeout.map_or(0, |t| t.as_millis().clamp(1, u32::MAX as u128) as u32));
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /*
+     * Makes commands that time out be sent `signal` (e.g. "TERM" or "KILL"), together with every process
+     * they started, rather than only have their channel closed, which leaves them running until they
+     * next write to it.
+     */
+    pub fn set_timeout_signal(&mut self, signal: Option<&str>) {
+        self.timeout_signal = signal.map(str::to_string);
+    }
+
+    pub fn timeout_signal(&self) -> Option<&str> {
+        self.timeout_signal.as_deref()
+    }
+
+    /*
+     * Starts `cmd` in the background and returns right away, with a handle to check on the process,
+     * read what it wrote, wait for it or kill it. Processes still running at the end of the run
+     * are killed, and the logs of all of them collected, before the machines are torn down.
+     */
+    pub fn spawn(&mut self, cmd: &str) -> Result<Process, Error> {
+        let process = Process::start(self, cmd)?;
+        debug!(self.log, "spawned process"; "cmd" => cmd, "pid" => process.pid);
+        self.spawned.lock().unwrap().push(process.clone());
+        Ok(process)
+    }
+
+    pub(crate) fn take_spawned(&self) -> Vec<Process> {
+        std::mem::take(&mut *self.spawned.lock().unwrap())
+    }
+
+    /*
+     * Another session over the same connection, sharing the processes spawned through it.
+     * The two must not be used at the same time.
+     */
+    pub(crate) fn try_clone(&self) -> Result<Session, Error> {
+        Ok(Session {
+            ssh: self.ssh.clone(),
+            _stream: self._stream.try_clone().context("failed to clone ssh connection")?,
+            log: self.log.clone(),
+            timeout: self.timeout,
+            timeout_signal: self.timeout_signal.clone(),
+            spawned: self.spawned.clone(),
+        })
+    }
+
+    /*
+     * Makes stream log the output of commands to `log`, e.g. one tagged with the machine set and ip.
+     */
+    pub(crate) fn set_logger(&mut self, log: slog::Logger) {
+        self.log = log;
+    }
+
+    pub(crate) fn log(&self) -> &slog::Logger {
+        &self.log
+    }
+
+    /*
+     * Runs `cmd` like exec, but logs every line it writes to stdout or stderr as soon as it is written,
+     * so that long commands (builds, package installs) can be followed while they run.
+     */
+    pub fn stream(&mut self, cmd: &str) -> Result<CommandOutput, Error> {
+        self.stream_with(cmd, |_, _| {})
+    }
+
+    /*
+     * Runs `cmd` like stream, and also hands every line to `on_line` together with the output it came from.
+     */
+    pub fn stream_with<F>(&mut self, cmd: &str, mut on_line: F) -> Result<CommandOutput, Error>
+    where F: FnMut(Stream, &str)
+    {
+        let log = self.log.new(o!("cmd" => cmd.to_string()));
+        self.run(cmd, self.timeout, &mut |stream, line| {
+            info!(log, "{}", line; "stream" => stream.name());
+            on_line(stream, line);
+        })
+    }
+
+    /*
+     * Runs `cmd`, handing every line of its output to `on_line` as soon as it is written,
+     * and gives up on it after `timeout`.
+     */
+    fn run(&mut self, cmd: &str, timeout: Option<Duration>, on_line: &mut dyn FnMut(Stream, &str)) -> Result<CommandOutput, Error> {
+        use std::io::{ErrorKind, Read};
+
+        let start = Instant::now();
+        // a command that may be signalled records its process group, which its processes share
+        let pid_file = match (timeout, &self.timeout_signal) {
+            (Some(_), Some(_)) => Some(format!("/tmp/burst-cmd-{:016x}.pid", rand::random::<u64>())),
+            _ => None,
+        };
+        let wrapped = match pid_file {
+            Some(ref pid_file) => format!("trap 'rm -f {f}' EXIT; echo $$ > {f}\n{}", cmd, f = pid_file),
+            None => cmd.to_string(),
+        };
         let mut channel = self.ssh
             .channel_session()
             .context(format!("failed to create ssh channel for command '{}'", cmd))?;
-        
-        channel.exec(cmd)
+
+        channel.exec(&wrapped)
                 .context(format!("failed to execute command '{}'", cmd))?;
-        
-        let mut s = String::new(); 
-        
-        channel.read_to_string(&mut s)
-                .context(format!("failed to read results of command '{}'", cmd))?;
 
-        
+        let mut outputs = [
+            (Stream::Stdout, LineBuffer::default(), false),
+            (Stream::Stderr, LineBuffer::default(), false),
+        ];
+        let mut buf = [0u8; 8192];
+        let mut idle = Duration::from_millis(1);
+        // both outputs are read as they come, which needs a non-blocking session
+        self.ssh.set_blocking(false);
+        let read = (|| -> std::io::Result<bool> {
+            while outputs.iter().any(|&(_, _, done)| !done) {
+                if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+                    return Ok(false);
+                }
+                let mut got = false;
+                for (stream, lines, done) in outputs.iter_mut().filter(|(_, _, done)| !*done) {
+                    let stream = *stream;
+                    let res = match stream {
+                        Stream::Stdout => channel.read(&mut buf),
+                        Stream::Stderr => channel.stderr().read(&mut buf),
+                    };
+                    match res {
+                        Ok(0) => *done = true,
+                        Ok(n) => {
+                            got = true;
+                            lines.push(&buf[..n], |line| on_line(stream, line));
+                        }
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                if got {
+                    idle = Duration::from_millis(1);
+                } else {
+                    thread::sleep(idle);
+                    idle = (idle * 2).min(Duration::from_millis(20));
+                }
+            }
+            Ok(true)
+        })();
+        self.ssh.set_blocking(true);
+        let completed = read.context(format!("failed to read results of command '{}'", cmd))?;
+
+        if !completed {
+            let timeout = timeout.expect("only commands with a timeout time out");
+            if let (Some(pid_file), Some(signal)) = (pid_file, self.timeout_signal.take()) {
+                let kill = format!("kill -{} -- -$(cat {f}) && rm -f {f}", quote(&signal), f = pid_file);
+                if let Err(e) = self.run(&kill, Some(SIGNAL_TIMEOUT), &mut |_, _| {}) {
+                    warn!(self.log, "failed to signal timed out command: {}", e; "cmd" => cmd);
+                }
+                self.timeout_signal = Some(signal);
+            }
+            // the channel is closed when dropped, without waiting for the command
+            let _ = channel.close();
+            return Err(CommandTimeout { cmd: cmd.to_string(), timeout }.into());
+        }
+
+        let [(_, stdout, _), (_, stderr, _)] = outputs;
+        let stdout = stdout.finish(|line| on_line(Stream::Stdout, line));
+        let stderr = stderr.finish(|line| on_line(Stream::Stderr, line));
+
         channel.wait_close()
             .context(format!("command '{}' never compeleted", cmd))?;
-    
-        Ok(s) 
+        let status = exit_status(&channel).context(format!("no exit status for command '{}'", cmd))?;
+
+        Ok(CommandOutput {
+            status,
+            stdout,
+            stderr,
+            duration: start.elapsed(),
+        })
+    }
+
+    /*
+     * Runs `cmd` like exec, but fails with CommandFailed if it exits with a non-zero status.
+     */
+    pub fn exec_checked(&mut self, cmd: &str) -> Result<CommandOutput, Error> {
+        let output = self.exec(cmd)?;
+        if !output.success() {
+            return Err(CommandFailed { cmd: cmd.to_string(), output }.into());
+        }
+        Ok(output)
+    }
+}
+
+/*
+ * `s` quoted as a single word for the remote shell.
+ */
+pub(crate) fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/*
+ * The exit status of the command of a closed channel, -1 if it was killed by a signal.
+ */
+fn exit_status(channel: &ssh2::Channel) -> Result<i32, ssh2::Error> {
+    match channel.exit_signal() {
+        Ok(signal) if signal.exit_signal.is_some() => Ok(-1),
+        _ => channel.exit_status(),
     }
 }
 
+use crate::spawn::Process;
 use std::ops::{Deref, DerefMut};
 impl Deref for Session {
     type Target = ssh2::Session;
@@ -81,3 +455,58 @@ impl DerefMut for Session {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_command_shows_the_tail_of_stderr() {
+        let stderr: String = (1..=15).map(|i| format!("error {}\n", i)).collect();
+        let failed = CommandFailed {
+            cmd: "make".to_string(),
+            output: CommandOutput {
+                status: 2,
+                stdout: String::new(),
+                stderr: stderr + "\n",
+                duration: Duration::from_secs(1),
+            },
+        };
+        let msg = failed.to_string();
+        assert!(msg.starts_with("command 'make' exited with status 2: error 6\n"));
+        assert!(msg.ends_with("error 15"));
+        assert!(!msg.contains("error 5\n"));
+
+        let quiet = CommandFailed {
+            cmd: "false".to_string(),
+            output: CommandOutput { status: 1, stdout: String::new(), stderr: String::new(), duration: Duration::ZERO },
+        };
+        assert_eq!(quiet.to_string(), "command 'false' exited with status 1");
+    }
+
+    #[test]
+    fn timeouts_are_told_apart_from_failures() {
+        let err: Error = CommandTimeout { cmd: "make".to_string(), timeout: Duration::from_millis(1500) }.into();
+        assert_eq!(err.to_string(), "command 'make' timed out after 1.5s");
+        assert!(err.downcast_ref::<CommandTimeout>().is_some());
+        assert!(err.downcast_ref::<CommandFailed>().is_none());
+    }
+
+    #[test]
+    fn quotes_for_the_remote_shell() {
+        assert_eq!(quote("bin/server"), "'bin/server'");
+        assert_eq!(quote("it's $HOME"), "'it'\\''s $HOME'");
+    }
+
+    #[test]
+    fn line_buffer_hands_out_complete_lines() {
+        let mut lines = Vec::new();
+        let mut buffer = LineBuffer::default();
+        buffer.push(b"Compiling foo\r\nCompil", |l| lines.push(l.to_string()));
+        assert_eq!(lines, ["Compiling foo"]);
+        buffer.push(b"ing bar\n\nFinish", |l| lines.push(l.to_string()));
+        assert_eq!(lines, ["Compiling foo", "Compiling bar", ""]);
+        let all = buffer.finish(|l| lines.push(l.to_string()));
+        assert_eq!(lines, ["Compiling foo", "Compiling bar", "", "Finish"]);
+        assert_eq!(all, "Compiling foo\r\nCompiling bar\n\nFinish");
+    }
+}